@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+mod setup;
+
+// a random memory image, which replaces the dictionary built by init (and
+// the variables below it) before the prelude is loaded through it
+fuzz_target!(|data: &[u8]| {
+    let mut vm = setup::vm(b"1 2 + . words 5 execute\n");
+    vm.init();
+    let memory = vm.memory_mut();
+    let len = memory.len().min(data.len());
+    memory[..len].copy_from_slice(&data[..len]);
+    setup::run(&mut vm);
+});
@@ -0,0 +1,37 @@
+use std::io::Cursor;
+use std::sync::Once;
+
+use froth::{Limits, VM};
+
+// Run in a scratch directory holding a copy of the prelude, so that the
+// file and block words can't touch anything that matters.
+fn enter_scratch_dir() {
+    static SCRATCH: Once = Once::new();
+    SCRATCH.call_once(|| {
+        let dir = std::env::temp_dir().join(format!("froth-fuzz-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prelude = concat!(env!("CARGO_MANIFEST_DIR"), "/../prelude.f");
+        std::fs::copy(prelude, dir.join("prelude.f")).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+    });
+}
+
+pub fn vm(input: &[u8]) -> VM {
+    enter_scratch_dir();
+    let mut vm = VM::with_input(Cursor::new(input.to_vec()));
+    vm.set_output(std::io::sink());
+    vm.set_limits(Limits {
+        max_steps: Some(100_000),
+        max_memory: Some(1 << 20),
+        ..Limits::default()
+    });
+    vm
+}
+
+// every error should be reported by step rather than panicking
+pub fn run(vm: &mut VM) {
+    while vm.is_running() {
+        let _ = vm.step();
+        vm.take_errors();
+    }
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+mod setup;
+
+// random source code, read after the prelude
+fuzz_target!(|data: &[u8]| {
+    let mut vm = setup::vm(data);
+    vm.init();
+    setup::run(&mut vm);
+});
@@ -11,14 +11,17 @@
 : 1-        1 - ;
 : 4+        4 + ;
 : 4-        4 - ;
+: cell+     cell + ;
+: cell-     cell - ;
+: cells     cell * ;
 
 : >dfa      >cfa 1+ align ;
-: hide      word find hidden ;
-
 : bl        32 ;
+: hide      bl word find hidden ;
+
 : space     bl emit ;
 : cr        10 emit ;
-: char      word drop c@ ;
+: char      bl word drop c@ ;
 
 : literal       ' lit , , ;             immediate
 : 'A'           [ char A ] literal ;
@@ -31,18 +34,18 @@
 : '>'           [ char > ] literal ;
 : '"'           [ char " ] literal ;
 
-: [compile]     word find >cfa , ;      immediate
+: [compile]     bl word find >cfa , ;      immediate
 : recurse       latest @ >cfa , ;       immediate
 
 : if            ' 0branch , here @ 0 , ;
                                         immediate
 : unless        ' not , [compile] if ;  immediate
-: then          dup here @ swap - 4+ swap ! ;
+: then          dup here @ swap - cell+ swap ! ;
                                         immediate
 : else          ' branch , here @ 0 , swap [compile] then ;
                                         immediate
 : begin         here @ ;                immediate
-: end-loop      here @ - 4+ , ;
+: end-loop      here @ - cell+ , ;
 : until         ' 0branch , end-loop ;
                                         immediate
 : again         ' branch , end-loop ;
@@ -66,6 +69,9 @@ hide end-loop
 ( The previous block extends the environment to include a comment parser,
 so from this point we can actually include comments in the prelude! )
 
+( A line comment runs to the end of the line. )
+: \             source swap drop >in ! ; immediate
+
 ( Define some extended stack manipulation primitives. )
 
 : over      >r dup r> swap ;
@@ -97,6 +103,8 @@ so from this point we can actually include comments in the prelude! )
 : 0<=       0 <= ;
 : 0>=       0 >= ;
 
+: s>d       dup 0< if -1 else 0 then ;
+
 : +!        tuck @ + swap ! ;
 : -!        tuck @ swap - swap ! ;
 
@@ -106,12 +114,12 @@ so from this point we can actually include comments in the prelude! )
 : decimal   10 base ! ;
 : hex       16 base ! ;
 
-: u.        base @ /mod
+: u.        0 base @ um/mod
             ( Print the quotient )
             ?dup if recurse then
             ( Print the remainder )
             dup 10 < if '0' else 10 - 'a' then + emit ;
-: uwidth    base @ / ?dup if recurse 1+ else 1 then ;
+: uwidth    0 base @ um/mod nip ?dup if recurse 1+ else 1 then ;
 : u.r       swap dup uwidth rot swap - spaces u. ;
 : .r        swap dup 0< if
                 negate 1 swap rot 1-
@@ -140,7 +148,7 @@ so from this point we can actually include comments in the prelude! )
                 begin key dup '"' <> while c, repeat
                 drop
                 dup here @ swap -
-                4- swap ! align
+                cell- swap ! align
             else
                 ( If interpreting, copy to 'here' temporarily )
                 here @
@@ -171,7 +179,7 @@ so from this point we can actually include comments in the prelude! )
 
 ( Some utilities to display the dictionary )
 
-: id.       4+ dup c@ length-mask and
+: id.       cell+ dup c@ length-mask and
             begin
                 dup 0>
             while
@@ -181,9 +189,9 @@ so from this point we can actually include comments in the prelude! )
             repeat 2drop ;
 
 : ?hidden
-            4+ c@ hidden-flag and ;
+            cell+ c@ hidden-flag and ;
 : ?immediate
-            4+ c@ immediate-flag and ;
+            cell+ c@ immediate-flag and ;
 
 : words     latest @
             begin ?dup
@@ -194,6 +202,35 @@ so from this point we can actually include comments in the prelude! )
                 @
             repeat cr ;
 
+( Block utilities. Loading a block runs its contents through the outer
+  interpreter, so thru keeps its loop state on the return stack in case
+  the blocks leave anything on the data stack. )
+
+: thru      1+ swap
+            begin
+                2dup >
+            while
+                swap >r dup >r load r> r> swap 1+
+            repeat 2drop ;
+
+: list      block 0
+            begin
+                dup 16 <
+            while
+                cr dup 2 u.r space
+                2dup 64 * + 64 tell
+                1+
+            repeat 2drop cr ;
+
+( Tasks take turns to run, switching when the running task calls pause or
+  stop; the outer interpreter pauses between words. task name makes a task,
+  which name identifies by pushing the address of its user area, and
+  name activate runs the rest of the current definition in that task. )
+
+: task      bl word create 0 c, align
+            ' lit , here @ 2 cells + , ' exit ,
+            here @ user-size allot (task) ;
+
 ( And to finish off with a sense of pride and accomplishment for everything we have made here ... )
 : count-words
             0 latest @
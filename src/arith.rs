@@ -0,0 +1,159 @@
+use crate::{Cell, SignedCell, VMErrorKind, VMResult, VMSuccess, VM};
+
+// Shifts and bit numbers count from the least significant bit. A shift by
+// the cell width or more shifts every bit out, and a bit number beyond the
+// top of the cell names a bit that is always clear, so setting or clearing
+// it leaves the cell unchanged.
+
+const CELL_BITS: Cell = Cell::BITS as Cell;
+
+// how signed division rounds a quotient that isn't whole: symmetric
+// division rounds towards zero, so the remainder takes the sign of the
+// dividend, and floored division rounds towards negative infinity, so the
+// remainder takes the sign of the divisor
+#[derive(Clone, Copy)]
+pub(crate) enum Rounding {
+    Symmetric,
+    Floored,
+}
+
+// A double cell number is two cells, with the most significant cell on
+// top of the stack. Doubles and the products of two cells are held in 128
+// bits, which is wide enough whatever the cell size.
+fn double(low: Cell, high: Cell) -> i128 {
+    i128::from(high as SignedCell) << Cell::BITS | low as i128
+}
+
+fn unsigned_double(low: Cell, high: Cell) -> u128 {
+    u128::from(high) << Cell::BITS | u128::from(low)
+}
+
+// ( -- rem quot ), failing if the divisor is zero or the quotient doesn't
+// fit in a cell
+fn divide(dividend: i128, divisor: Cell, rounding: Rounding) -> VMResult<(Cell, Cell)> {
+    let divisor = i128::from(divisor as SignedCell);
+    let (mut quot, mut rem) = dividend
+        .checked_div(divisor)
+        .zip(dividend.checked_rem(divisor))
+        .ok_or(VMErrorKind::MathError)?;
+    if matches!(rounding, Rounding::Floored) && rem != 0 && (rem < 0) != (divisor < 0) {
+        quot -= 1;
+        rem += divisor;
+    }
+    let quot = SignedCell::try_from(quot).map_err(|_| VMErrorKind::MathError)?;
+    Ok((rem as Cell, quot as Cell))
+}
+
+pub(crate) fn flag(b: bool) -> Cell {
+    b.into()
+}
+
+// ( x u -- x' )
+pub(crate) fn lshift(x: Cell, u: Cell) -> Cell {
+    if u < CELL_BITS {
+        x << u
+    } else {
+        0
+    }
+}
+
+// ( x u -- x' ), filling with zeros
+pub(crate) fn rshift(x: Cell, u: Cell) -> Cell {
+    if u < CELL_BITS {
+        x >> u
+    } else {
+        0
+    }
+}
+
+// ( x u -- x' ), filling with copies of the sign bit
+pub(crate) fn arshift(x: Cell, u: Cell) -> Cell {
+    (x as SignedCell >> u.min(CELL_BITS - 1)) as Cell
+}
+
+// bit? ( x u -- flag )
+pub(crate) fn bit_test(x: Cell, u: Cell) -> Cell {
+    flag(rshift(x, u) & 1 != 0)
+}
+
+// set-bit ( x u -- x' )
+pub(crate) fn bit_set(x: Cell, u: Cell) -> Cell {
+    x | lshift(1, u)
+}
+
+// clear-bit ( x u -- x' )
+pub(crate) fn bit_clear(x: Cell, u: Cell) -> Cell {
+    x & !lshift(1, u)
+}
+
+impl VM {
+    // ( a b -- c )
+    pub(crate) fn binary(&mut self, op: fn(Cell, Cell) -> Cell) -> VMSuccess {
+        let b = self.pop_data()?;
+        let a = self.pop_data()?;
+        self.push_data(op(a, b))
+    }
+
+    // ( a -- b )
+    pub(crate) fn unary(&mut self, op: fn(Cell) -> Cell) -> VMSuccess {
+        let a = self.pop_data()?;
+        self.push_data(op(a))
+    }
+
+    fn push_division(&mut self, (rem, quot): (Cell, Cell)) -> VMSuccess {
+        self.push_data(rem)?;
+        self.push_data(quot)
+    }
+
+    // sm/rem and fm/mod ( d n -- rem quot )
+    pub(crate) fn divide_double(&mut self, rounding: Rounding) -> VMSuccess {
+        let n = self.pop_data()?;
+        let high = self.pop_data()?;
+        let low = self.pop_data()?;
+        self.push_division(divide(double(low, high), n, rounding)?)
+    }
+
+    // /mod ( n1 n2 -- rem quot ), which is symmetric
+    pub(crate) fn div_mod(&mut self) -> VMSuccess {
+        let n2 = self.pop_data()?;
+        let n1 = self.pop_data()?;
+        let n1 = i128::from(n1 as SignedCell);
+        self.push_division(divide(n1, n2, Rounding::Symmetric)?)
+    }
+
+    // */ ( n1 n2 n3 -- quot ) and */mod ( n1 n2 n3 -- rem quot ), which
+    // divide the double cell product of n1 and n2 by n3, so that it can't
+    // overflow
+    pub(crate) fn star_slash(&mut self, remainder: bool) -> VMSuccess {
+        let n3 = self.pop_data()?;
+        let n2 = self.pop_data()?;
+        let n1 = self.pop_data()?;
+        let product = i128::from(n1 as SignedCell) * i128::from(n2 as SignedCell);
+        let (rem, quot) = divide(product, n3, Rounding::Symmetric)?;
+        if remainder {
+            self.push_data(rem)?;
+        }
+        self.push_data(quot)
+    }
+
+    // um/mod ( ud u -- urem uquot )
+    pub(crate) fn um_mod(&mut self) -> VMSuccess {
+        let u = u128::from(self.pop_data()?);
+        let high = self.pop_data()?;
+        let low = self.pop_data()?;
+        let ud = unsigned_double(low, high);
+        let quot = ud.checked_div(u).ok_or(VMErrorKind::MathError)?;
+        let quot = Cell::try_from(quot).map_err(|_| VMErrorKind::MathError)?;
+        self.push_division(((ud % u) as Cell, quot))
+    }
+
+    // within ( n lo hi -- flag ) whether lo <= n < hi, treating the range
+    // as running from lo up to hi and wrapping round if hi is below lo,
+    // which works for signed and unsigned numbers alike
+    pub(crate) fn within(&mut self) -> VMSuccess {
+        let hi = self.pop_data()?;
+        let lo = self.pop_data()?;
+        let n = self.pop_data()?;
+        self.push_data(flag(n.wrapping_sub(lo) < hi.wrapping_sub(lo)))
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::{Cell, VMErrorKind, VMResult, VMSuccess, VM};
+
+pub(crate) const BLOCK_SIZE: Cell = 1024;
+const NUM_BUFFERS: usize = 4;
+
+struct BlockBuffer {
+    addr: Cell,
+    block: Option<Cell>,
+    dirty: bool,
+}
+
+// Block buffers are allocated on the heap the first time they are
+// needed, and reassigned round-robin once they are all in use.
+pub(crate) struct Blocks {
+    pub(crate) path: PathBuf,
+    buffers: Vec<BlockBuffer>,
+    current: Option<usize>,
+    next_victim: usize,
+}
+
+impl Default for Blocks {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("blocks.fb"),
+            buffers: Vec::new(),
+            current: None,
+            next_victim: 0,
+        }
+    }
+}
+
+// the offset of the block in the file, which must leave room for the whole
+// block below the largest file offset; block numbers start at 1
+fn block_offset(block: Cell) -> VMResult<u64> {
+    u64::from(block)
+        .checked_mul(u64::from(BLOCK_SIZE))
+        .and_then(|end| end.checked_sub(u64::from(BLOCK_SIZE)))
+        .ok_or(VMErrorKind::InvalidBlock(block))
+}
+
+impl VM {
+    fn read_block(&mut self, block: Cell, addr: Cell) -> VMSuccess {
+        if !self.file_access {
+            return Err(VMErrorKind::IOError);
+        }
+        let mut data = vec![b' '; BLOCK_SIZE as usize];
+        // a block beyond the end of the file (or a missing file) is blank
+        if let Ok(mut file) = File::open(&self.blocks.path) {
+            file.seek(SeekFrom::Start(block_offset(block)?))
+                .map_err(|_| VMErrorKind::IOError)?;
+            let mut count = 0;
+            while count < data.len() {
+                match file.read(&mut data[count..]) {
+                    Ok(0) => break,
+                    Ok(n) => count += n,
+                    Err(_) => return Err(VMErrorKind::IOError),
+                }
+            }
+        }
+        self.bytes_mut(addr, BLOCK_SIZE)?.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_block(&mut self, i: usize) -> VMSuccess {
+        let buffer = &self.blocks.buffers[i];
+        if let (Some(block), true) = (buffer.block, buffer.dirty) {
+            if !self.file_access {
+                return Err(VMErrorKind::IOError);
+            }
+            let data = self.read_bytes(buffer.addr, BLOCK_SIZE)?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.blocks.path)
+                .map_err(|_| VMErrorKind::IOError)?;
+            file.seek(SeekFrom::Start(block_offset(block)?))
+                .and_then(|_| file.write_all(data))
+                .map_err(|_| VMErrorKind::IOError)?;
+            self.blocks.buffers[i].dirty = false;
+        }
+        Ok(())
+    }
+
+    // find or assign a buffer for the block, returning its index and
+    // whether it already holds the block's contents
+    fn assign_buffer(&mut self, block: Cell) -> VMResult<(usize, bool)> {
+        block_offset(block)?;
+        if let Some(i) = self
+            .blocks
+            .buffers
+            .iter()
+            .position(|b| b.block == Some(block))
+        {
+            return Ok((i, true));
+        }
+        let i = if self.blocks.buffers.len() < NUM_BUFFERS {
+            let addr = self
+                .allocate(BLOCK_SIZE)?
+                .ok_or(VMErrorKind::MemoryLimitExceeded)?;
+            self.blocks.buffers.push(BlockBuffer {
+                addr,
+                block: None,
+                dirty: false,
+            });
+            self.blocks.buffers.len() - 1
+        } else {
+            let i = self.blocks.next_victim;
+            self.blocks.next_victim = (i + 1) % NUM_BUFFERS;
+            self.write_block(i)?;
+            i
+        };
+        self.blocks.buffers[i].block = Some(block);
+        Ok((i, false))
+    }
+
+    pub(crate) fn block(&mut self, block: Cell) -> VMResult<Cell> {
+        let (i, loaded) = self.assign_buffer(block)?;
+        let addr = self.blocks.buffers[i].addr;
+        if !loaded {
+            if let Err(e) = self.read_block(block, addr) {
+                self.blocks.buffers[i].block = None;
+                return Err(e);
+            }
+        }
+        self.blocks.current = Some(i);
+        Ok(addr)
+    }
+
+    pub(crate) fn buffer(&mut self, block: Cell) -> VMResult<Cell> {
+        let (i, _) = self.assign_buffer(block)?;
+        self.blocks.current = Some(i);
+        Ok(self.blocks.buffers[i].addr)
+    }
+
+    pub(crate) fn update(&mut self) {
+        if let Some(i) = self.blocks.current {
+            self.blocks.buffers[i].dirty = true;
+        }
+    }
+
+    pub(crate) fn save_buffers(&mut self) -> VMSuccess {
+        for i in 0..self.blocks.buffers.len() {
+            self.write_block(i)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> VMSuccess {
+        self.save_buffers()?;
+        for buffer in &mut self.blocks.buffers {
+            buffer.block = None;
+        }
+        self.blocks.current = None;
+        Ok(())
+    }
+
+    pub(crate) fn load(&mut self, block: Cell) -> VMSuccess {
+        let addr = self.block(block)?;
+        self.interpret_block(block, addr, BLOCK_SIZE)
+    }
+}
@@ -1,68 +1,119 @@
+use crate::files::{FAM_BIN, FAM_READ, FAM_WRITE};
+use crate::peephole::Words;
+use crate::source::TIB_SIZE;
+use crate::tasks::{Tasks, USER_SIZE};
 use crate::{
-    align_addr, Op, ADDR_BASE, ADDR_HERE, ADDR_LATEST, ADDR_STATE, HIDDEN_FLAG, IMMEDIATE_FLAG,
-    LENGTH_MASK, VM,
+    align_addr, Cell, Op, SignedCell, ADDR_BASE, ADDR_HERE, ADDR_LATEST, ADDR_STATE, CELL_SIZE,
+    HIDDEN_FLAG, IMMEDIATE_FLAG, LENGTH_MASK, VM,
 };
 
 impl VM {
     // note: add_builtin_word and add_colon_word don't return
     // a Result because they should only ever be called at init
-    fn add_builtin_word(&mut self, word: &str, op: Op) -> u32 {
-        self.buffer_word(word);
+    fn add_builtin_word(&mut self, word: &str, op: Op) -> Cell {
+        self.buffer_word(word).unwrap();
         self.create().unwrap();
-        let xt = self.read_u32(ADDR_HERE).unwrap();
+        let xt = self.read_cell(ADDR_HERE).unwrap();
         self.write_u8_here(op.into()).unwrap();
         xt
     }
 
-    fn add_colon_word(&mut self, word: &str, def: Vec<u32>) -> u32 {
-        self.buffer_word(word);
+    fn add_colon_word(&mut self, word: &str, def: Vec<Cell>) -> Cell {
+        self.buffer_word(word).unwrap();
         self.create().unwrap();
-        let xt = self.read_u32(ADDR_HERE).unwrap();
+        let xt = self.read_cell(ADDR_HERE).unwrap();
         self.write_u8_here(Op::DoColonDef.into()).unwrap();
         self.align().unwrap();
         for item in def {
-            self.write_u32_here(item).unwrap();
+            self.write_cell_here(item).unwrap();
         }
         xt
     }
 
-    fn set_entry_point(&mut self, xt: u32) {
+    fn hide_latest(&mut self) {
+        self.push_data(self.read_cell(ADDR_LATEST).unwrap())
+            .unwrap();
+        self.hidden().unwrap();
+    }
+
+    fn set_entry_point(&mut self, xt: Cell) {
         assert!(self.read_u8(xt).unwrap() == 0);
         let addr = align_addr(xt + 1);
         self.entry = addr;
         self.pc = addr;
     }
 
-    pub(crate) fn init(&mut self) {
+    pub fn init(&mut self) {
         let lit = self.add_builtin_word("lit", Op::Lit);
         self.lit = lit; // store it for use in compilation
 
-        let _dup = self.add_builtin_word("dup", Op::Dup);
-        let _drop = self.add_builtin_word("drop", Op::Drop);
-        let _swap = self.add_builtin_word("swap", Op::Swap);
+        let dup = self.add_builtin_word("dup", Op::Dup);
+        let drop = self.add_builtin_word("drop", Op::Drop);
+        let swap = self.add_builtin_word("swap", Op::Swap);
         let _depth = self.add_builtin_word("depth", Op::Depth);
-        let _tor = self.add_builtin_word(">r", Op::ToR);
-        let _fromr = self.add_builtin_word("r>", Op::FromR);
+        let to_r = self.add_builtin_word(">r", Op::ToR);
+        let from_r = self.add_builtin_word("r>", Op::FromR);
         let fetch = self.add_builtin_word("@", Op::Fetch);
         let _cfetch = self.add_builtin_word("c@", Op::CFetch);
         let _cstore = self.add_builtin_word("!", Op::Store);
         let align = self.add_builtin_word("align", Op::Align);
         let _store = self.add_builtin_word("c!", Op::CStore);
-        let _add = self.add_builtin_word("+", Op::Add);
+        let add = self.add_builtin_word("+", Op::Add);
         let _subtract = self.add_builtin_word("-", Op::Subtract);
         let _multiply = self.add_builtin_word("*", Op::Multiply);
         let _divide = self.add_builtin_word("/mod", Op::DivMod);
+        let _sm_rem = self.add_builtin_word("sm/rem", Op::SmRem);
+        let _fm_mod = self.add_builtin_word("fm/mod", Op::FmMod);
+        let _um_mod = self.add_builtin_word("um/mod", Op::UmMod);
+        let _star_slash = self.add_builtin_word("*/", Op::StarSlash);
+        let _star_slash_mod = self.add_builtin_word("*/mod", Op::StarSlashMod);
         let _equals = self.add_builtin_word("=", Op::Equals);
         let _lt = self.add_builtin_word("<", Op::LessThan);
         let _gt = self.add_builtin_word(">", Op::GreaterThan);
         let _and = self.add_builtin_word("and", Op::And);
-        let _or = self.add_builtin_word("or", Op::Or);
+        let or = self.add_builtin_word("or", Op::Or);
         let _xor = self.add_builtin_word("xor", Op::Xor);
         let _invert = self.add_builtin_word("invert", Op::Invert);
+        let _u_lt = self.add_builtin_word("u<", Op::ULessThan);
+        let _u_gt = self.add_builtin_word("u>", Op::UGreaterThan);
+        let _lshift = self.add_builtin_word("lshift", Op::LShift);
+        let _rshift = self.add_builtin_word("rshift", Op::RShift);
+        let _arshift = self.add_builtin_word("arshift", Op::ARShift);
+        let _two_star = self.add_builtin_word("2*", Op::TwoStar);
+        let _two_slash = self.add_builtin_word("2/", Op::TwoSlash);
+        let _within = self.add_builtin_word("within", Op::Within);
+        let _min = self.add_builtin_word("min", Op::Min);
+        let _max = self.add_builtin_word("max", Op::Max);
+        let _abs = self.add_builtin_word("abs", Op::Abs);
+        let _bit_test = self.add_builtin_word("bit?", Op::BitTest);
+        let _bit_set = self.add_builtin_word("set-bit", Op::BitSet);
+        let _bit_clear = self.add_builtin_word("clear-bit", Op::BitClear);
         let _key = self.add_builtin_word("key", Op::Key);
         let word = self.add_builtin_word("word", Op::Word);
+        let _parse = self.add_builtin_word("parse", Op::Parse);
+        let _parse_name = self.add_builtin_word("parse-name", Op::ParseName);
+        let _source = self.add_builtin_word("source", Op::Source);
+        let _refill = self.add_builtin_word("refill", Op::Refill);
+        let _evaluate = self.add_builtin_word("evaluate", Op::Evaluate);
         let _emit = self.add_builtin_word("emit", Op::Emit);
-        let _litstring = self.add_builtin_word("litstring", Op::LitString);
+        let _xchar_fetch = self.add_builtin_word("xc@+", Op::XCharFetch);
+        let _xchar_store = self.add_builtin_word("xc!+", Op::XCharStore);
+        let _xchar_size = self.add_builtin_word("x-size", Op::XCharSize);
+        let _xemit = self.add_builtin_word("xemit", Op::XEmit);
+        let _xkey = self.add_builtin_word("xkey", Op::XKey);
+        let _compare = self.add_builtin_word("compare", Op::Compare);
+        let _search = self.add_builtin_word("search", Op::Search);
+        let _slash_string = self.add_builtin_word("/string", Op::SlashString);
+        let _minus_trailing = self.add_builtin_word("-trailing", Op::MinusTrailing);
+        let _blank = self.add_builtin_word("blank", Op::Blank);
+        let _cmove = self.add_builtin_word("cmove", Op::CMove);
+        let _cmove_up = self.add_builtin_word("cmove>", Op::CMoveUp);
+        let _move = self.add_builtin_word("move", Op::Move);
+        let _fill = self.add_builtin_word("fill", Op::Fill);
+        let _erase = self.add_builtin_word("erase", Op::Erase);
+        let _count = self.add_builtin_word("count", Op::Count);
+        let _type = self.add_builtin_word("type", Op::Type);
+        let lit_string = self.add_builtin_word("litstring", Op::LitString);
         let _find = self.add_builtin_word("find", Op::Find);
         let _number = self.add_builtin_word("number", Op::Number);
         let _to_cfa = self.add_builtin_word(">cfa", Op::ToCFA);
@@ -74,14 +125,84 @@ impl VM {
         let ccomma = self.add_builtin_word("c,", Op::CComma);
         let _immediate = self.add_builtin_word("immediate", Op::Immediate);
         self.immediate().unwrap(); // 'immediate' is an immediate word
+        let _inline = self.add_builtin_word("inline", Op::Inline);
+        self.immediate().unwrap(); // and so is 'inline'
         let hidden = self.add_builtin_word("hidden", Op::Hidden);
-        let _tick = self.add_builtin_word("'", Op::Tick);
-        let _execute = self.add_builtin_word("execute", Op::Execute);
+        let tick = self.add_builtin_word("'", Op::Tick);
+        let execute = self.add_builtin_word("execute", Op::Execute);
         let branch = self.add_builtin_word("branch", Op::Branch);
-        let _branchif0 = self.add_builtin_word("0branch", Op::BranchIfZero);
+        let branch_if_zero = self.add_builtin_word("0branch", Op::BranchIfZero);
         let exit = self.add_builtin_word("exit", Op::Exit);
         let reset = self.add_builtin_word("reset", Op::Reset);
         let interpret = self.add_builtin_word("interpret", Op::Interpret);
+        let _allot = self.add_builtin_word("allot", Op::Allot);
+        let _unused = self.add_builtin_word("unused", Op::Unused);
+        let _allocate = self.add_builtin_word("allocate", Op::Allocate);
+        let _free = self.add_builtin_word("free", Op::Free);
+        let _resize = self.add_builtin_word("resize", Op::Resize);
+        let _open_file = self.add_builtin_word("open-file", Op::OpenFile);
+        let _create_file = self.add_builtin_word("create-file", Op::CreateFile);
+        let _close_file = self.add_builtin_word("close-file", Op::CloseFile);
+        let _read_file = self.add_builtin_word("read-file", Op::ReadFile);
+        let _read_line = self.add_builtin_word("read-line", Op::ReadLine);
+        let _write_file = self.add_builtin_word("write-file", Op::WriteFile);
+        let _write_line = self.add_builtin_word("write-line", Op::WriteLine);
+        let _file_size = self.add_builtin_word("file-size", Op::FileSize);
+        let _file_position = self.add_builtin_word("file-position", Op::FilePosition);
+        let _reposition_file = self.add_builtin_word("reposition-file", Op::RepositionFile);
+        let _delete_file = self.add_builtin_word("delete-file", Op::DeleteFile);
+        let _rename_file = self.add_builtin_word("rename-file", Op::RenameFile);
+        let _block = self.add_builtin_word("block", Op::Block);
+        let _buffer = self.add_builtin_word("buffer", Op::Buffer);
+        let _update = self.add_builtin_word("update", Op::Update);
+        let _save_buffers = self.add_builtin_word("save-buffers", Op::SaveBuffers);
+        let _flush = self.add_builtin_word("flush", Op::Flush);
+        let _load = self.add_builtin_word("load", Op::Load);
+        let _test_start = self.add_builtin_word("T{", Op::TestStart);
+        let _test_results = self.add_builtin_word("->", Op::TestResults);
+        let _test_end = self.add_builtin_word("}T", Op::TestEnd);
+        let _new_task = self.add_builtin_word("(task)", Op::NewTask);
+        let activate = self.add_builtin_word("activate", Op::Activate);
+        let _pause = self.add_builtin_word("pause", Op::Pause);
+        let stop = self.add_builtin_word("stop", Op::Stop);
+        let _wake = self.add_builtin_word("wake", Op::Wake);
+        let _user_area = self.add_builtin_word("up@", Op::UserArea);
+        let optimise = self.add_builtin_word("(optimise)", Op::Optimise);
+        self.hide_latest();
+
+        // superinstructions, which are only compiled by the optimiser
+        let lit_add = self.add_builtin_word("(lit+)", Op::LitAdd);
+        self.hide_latest();
+        let dup_branch_if_zero = self.add_builtin_word("(dup-0branch)", Op::DupBranchIfZero);
+        self.hide_latest();
+        let nip = self.add_builtin_word("(nip)", Op::Nip);
+        self.hide_latest();
+        let over = self.add_builtin_word("(over)", Op::Over);
+        self.hide_latest();
+        let two_dup = self.add_builtin_word("(2dup)", Op::TwoDup);
+        self.hide_latest();
+        self.peephole.words = Words {
+            lit,
+            lit_string,
+            tick,
+            branch,
+            branch_if_zero,
+            dup,
+            drop,
+            swap,
+            add,
+            to_r,
+            from_r,
+            exit,
+            execute,
+            activate,
+            reset,
+            lit_add,
+            dup_branch_if_zero,
+            nip,
+            over,
+            two_dup,
+        };
 
         // expose builtin variables
         let _base = self.add_colon_word("base", vec![lit, ADDR_BASE, exit]);
@@ -92,13 +213,23 @@ impl VM {
         let _immediate_flag =
             self.add_colon_word("immediate-flag", vec![lit, IMMEDIATE_FLAG.into(), exit]);
         let _length_mask = self.add_colon_word("length-mask", vec![lit, LENGTH_MASK.into(), exit]);
+        let _user_size = self.add_colon_word("user-size", vec![lit, USER_SIZE, exit]);
+        let _cell = self.add_colon_word("cell", vec![lit, CELL_SIZE, exit]);
+
+        // file access methods
+        let _ro = self.add_colon_word("r/o", vec![lit, FAM_READ, exit]);
+        let _wo = self.add_colon_word("w/o", vec![lit, FAM_WRITE, exit]);
+        let _rw = self.add_colon_word("r/w", vec![lit, FAM_READ | FAM_WRITE, exit]);
+        let _bin = self.add_colon_word("bin", vec![lit, FAM_BIN, or, exit]);
         let _colon = self.add_colon_word(
             ":",
             vec![
+                lit,
+                b' '.into(),
                 word,
                 create,
                 lit,
-                Op::DoColonDef as u32,
+                Op::DoColonDef as Cell,
                 ccomma,
                 align,
                 latest,
@@ -110,10 +241,45 @@ impl VM {
         );
         let _semicolon = self.add_colon_word(
             ";",
-            vec![lit, exit, comma, latest, fetch, hidden, lbracket, exit],
+            vec![
+                lit, exit, comma, optimise, latest, fetch, hidden, lbracket, exit,
+            ],
         );
         self.immediate().unwrap(); // ';' is an immediate word
-        let quit = self.add_colon_word("quit", vec![reset, interpret, branch, -8i32 as u32]);
+        let quit = self.add_colon_word(
+            "quit",
+            vec![
+                reset,
+                interpret,
+                branch,
+                (-2 * CELL_SIZE as SignedCell) as Cell,
+            ],
+        );
+        // like quit, but used to interpret nested sources such as blocks
+        // and evaluated strings, so it mustn't reset the return stack; it's
+        // hidden because it only makes sense when entered by the VM itself
+        let nested = self.add_colon_word(
+            "(nested)",
+            vec![interpret, branch, (-(CELL_SIZE as SignedCell)) as Cell],
+        );
+        self.hide_latest();
+        self.nested_interpreter = align_addr(nested + 1);
+        // where a task goes when it returns from the code that activated it
+        let task_end = self.add_colon_word(
+            "(task-end)",
+            vec![stop, branch, (-(CELL_SIZE as SignedCell)) as Cell],
+        );
+        self.hide_latest();
+        let operator_user = self.read_cell(ADDR_HERE).unwrap();
+        self.allot(USER_SIZE as SignedCell).unwrap();
+        self.tasks = Tasks::new(operator_user, align_addr(task_end + 1));
+
+        // the input buffer, and >in
+        self.addr_to_in = self.read_cell(ADDR_HERE).unwrap();
+        self.allot(CELL_SIZE as SignedCell).unwrap();
+        self.tib = self.read_cell(ADDR_HERE).unwrap();
+        self.allot(TIB_SIZE as SignedCell).unwrap();
+        let _to_in = self.add_colon_word(">in", vec![lit, self.addr_to_in, exit]);
 
         self.set_entry_point(quit);
     }
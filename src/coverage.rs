@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::{Cell, Location, VM};
+
+// Cells are tagged with the source line that compiled them, and counted
+// each time they are executed (or read as the operand of a literal or
+// branch). A line is covered if any of its cells has run.
+#[derive(Default)]
+pub(crate) struct Coverage {
+    cell_locations: HashMap<Cell, Location>,
+    executed: HashMap<Cell, u64>,
+}
+
+impl Coverage {
+    pub(crate) fn compiled(&mut self, addr: Cell, location: Location) {
+        self.cell_locations.insert(addr, location);
+    }
+
+    pub(crate) fn executed(&mut self, addr: Cell) {
+        *self.executed.entry(addr).or_default() += 1;
+    }
+
+    // hit counts per line, per source file
+    fn lines(&self) -> BTreeMap<usize, BTreeMap<u32, u64>> {
+        let mut files: BTreeMap<usize, BTreeMap<u32, u64>> = BTreeMap::new();
+        for (addr, location) in &self.cell_locations {
+            let hits = self.executed.get(addr).copied().unwrap_or(0);
+            let line = files
+                .entry(location.file)
+                .or_default()
+                .entry(location.line)
+                .or_default();
+            *line = (*line).max(hits);
+        }
+        files
+    }
+}
+
+impl VM {
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::default());
+    }
+
+    pub(crate) fn cover(&mut self, addr: Cell) {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.executed(addr);
+        }
+    }
+
+    /// A summary of line coverage for each source file, listing the lines
+    /// that compiled code which never ran.
+    pub fn coverage_report(&self) -> Option<String> {
+        let coverage = self.coverage.as_ref()?;
+        let mut report = String::new();
+        for (file, lines) in coverage.lines() {
+            let hit = lines.values().filter(|&&hits| hits > 0).count();
+            let _ = writeln!(
+                report,
+                "{}: {}/{} lines ({:.1}%)",
+                self.source_names[file],
+                hit,
+                lines.len(),
+                100.0 * hit as f64 / lines.len() as f64
+            );
+            let missed: Vec<String> = lines
+                .iter()
+                .filter(|(_, &hits)| hits == 0)
+                .map(|(line, _)| line.to_string())
+                .collect();
+            if !missed.is_empty() {
+                let _ = writeln!(report, "  not run: {}", missed.join(", "));
+            }
+        }
+        Some(report)
+    }
+
+    /// Write line coverage in the lcov tracefile format.
+    pub fn write_lcov(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let coverage = match &self.coverage {
+            Some(coverage) => coverage,
+            None => return Ok(()),
+        };
+        writeln!(out, "TN:")?;
+        for (file, lines) in coverage.lines() {
+            writeln!(out, "SF:{}", self.source_names[file])?;
+            for (line, hits) in &lines {
+                writeln!(out, "DA:{},{}", line, hits)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(
+                out,
+                "LH:{}",
+                lines.values().filter(|&&hits| hits > 0).count()
+            )?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::path::PathBuf;
+
+use crate::{ADDR_LATEST, ADDR_STATE, CELL_SIZE, HIDDEN_FLAG, VM};
+
+const PROMPT: &str = ">";
+const CONTINUATION_PROMPT: &str = "...";
+
+// completes the word under the cursor from the visible dictionary entries,
+// which are refreshed before each line is read
+#[derive(Default)]
+struct DictionaryHelper {
+    words: Vec<String>,
+}
+
+impl Completer for DictionaryHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| c.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        let prefix = &line[start..pos];
+        let mut candidates: Vec<String> = self
+            .words
+            .iter()
+            .filter(|w| w.starts_with(prefix))
+            .cloned()
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for DictionaryHelper {
+    type Hint = String;
+}
+
+impl Highlighter for DictionaryHelper {}
+
+impl Validator for DictionaryHelper {}
+
+impl Helper for DictionaryHelper {}
+
+pub(crate) struct LineEditor {
+    editor: Editor<DictionaryHelper, DefaultHistory>,
+    history_path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    pub(crate) fn new() -> rustyline::Result<Self> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(DictionaryHelper::default()));
+        let history_path =
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".froth_history"));
+        if let Some(path) = &history_path {
+            // there won't be a history file the first time round
+            let _ = editor.load_history(path);
+        }
+        Ok(Self {
+            editor,
+            history_path,
+        })
+    }
+}
+
+impl VM {
+    fn visible_words(&self) -> Vec<String> {
+        let mut words = Vec::new();
+        let mut header_addr = self.read_cell(ADDR_LATEST).unwrap_or(0);
+        while header_addr != 0 {
+            let flags = self
+                .read_u8(header_addr.wrapping_add(CELL_SIZE))
+                .unwrap_or(HIDDEN_FLAG);
+            let hidden = flags & HIDDEN_FLAG != 0;
+            if !hidden {
+                if let Ok(name) = self.header_name(header_addr) {
+                    words.push(name);
+                }
+            }
+            header_addr = self.next_header(header_addr).unwrap_or(0);
+        }
+        words
+    }
+
+    // read a line from the terminal, returning None at end of input
+    pub(crate) fn read_terminal_line(&mut self) -> Option<Vec<u8>> {
+        let words = self.visible_words();
+        let compiling = self.read_cell(ADDR_STATE).unwrap_or(0) != 0;
+        let editor = self.editor.as_mut()?;
+        if let Some(helper) = editor.editor.helper_mut() {
+            helper.words = words;
+        }
+        let prompt = if compiling {
+            CONTINUATION_PROMPT
+        } else {
+            PROMPT
+        };
+        loop {
+            match editor.editor.readline(prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        let _ = editor.editor.add_history_entry(line.as_str());
+                        if let Some(path) = &editor.history_path {
+                            let _ = editor.editor.save_history(path);
+                        }
+                    }
+                    let mut bytes = line.into_bytes();
+                    bytes.push(13);
+                    return Some(bytes);
+                }
+                // ctrl-c abandons the current line
+                Err(ReadlineError::Interrupted) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+}
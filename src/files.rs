@@ -0,0 +1,287 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::{Cell, VMResult, VMSuccess, VM};
+
+// file access methods, as returned by r/o, w/o and r/w
+pub(crate) const FAM_READ: Cell = 1;
+pub(crate) const FAM_WRITE: Cell = 2;
+pub(crate) const FAM_BIN: Cell = 4;
+
+const IOR_FILE_IO: Cell = -37i32 as Cell;
+const IOR_NO_FILE: Cell = -38i32 as Cell;
+
+fn ior(err: std::io::Error) -> Cell {
+    match err.kind() {
+        ErrorKind::NotFound => IOR_NO_FILE,
+        _ => IOR_FILE_IO,
+    }
+}
+
+// split a u64 into a double cell, low cell first
+fn split_double(n: u64) -> (Cell, Cell) {
+    (n as Cell, (u128::from(n) >> Cell::BITS) as Cell)
+}
+
+// a double cell as a u64, if it fits
+fn join_double(low: Cell, high: Cell) -> Option<u64> {
+    u64::try_from(u128::from(high) << Cell::BITS | u128::from(low)).ok()
+}
+
+impl VM {
+    /// Allow or forbid access to the filesystem from Forth code. It's
+    /// allowed by default; with it forbidden, opening, creating, deleting
+    /// and renaming files fail with an i/o error ior, and so does reading
+    /// or writing blocks.
+    pub fn set_file_access(&mut self, enabled: bool) {
+        self.file_access = enabled;
+    }
+
+    fn alloc_file_id(&mut self, file: File) -> Cell {
+        // file ids start at 1 so that 0 is never a valid id
+        match self.files.iter().position(Option::is_none) {
+            Some(i) => {
+                self.files[i] = Some(file);
+                i as Cell + 1
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() as Cell
+            }
+        }
+    }
+
+    fn file(&mut self, id: Cell) -> Option<&mut File> {
+        let i = (id as usize).checked_sub(1)?;
+        self.files.get_mut(i)?.as_mut()
+    }
+
+    fn open_with(&mut self, options: &mut OpenOptions) -> VMSuccess {
+        let fam = self.pop_data()?;
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        let name = self.read_string(addr, len)?;
+        if !self.file_access {
+            self.push_data(0)?;
+            return self.push_data(IOR_FILE_IO);
+        }
+        let result = options
+            .read(fam & FAM_READ != 0)
+            .write(fam & FAM_WRITE != 0)
+            .open(name);
+        match result {
+            Ok(file) => {
+                let id = self.alloc_file_id(file);
+                self.push_data(id)?;
+                self.push_data(0)
+            }
+            Err(e) => {
+                self.push_data(0)?;
+                self.push_data(ior(e))
+            }
+        }
+    }
+
+    pub(crate) fn open_file(&mut self) -> VMSuccess {
+        self.open_with(&mut OpenOptions::new())
+    }
+
+    pub(crate) fn create_file(&mut self) -> VMSuccess {
+        self.open_with(OpenOptions::new().create(true).truncate(true))
+    }
+
+    pub(crate) fn close_file(&mut self) -> VMSuccess {
+        let id = self.pop_data()?;
+        let closed = (id as usize)
+            .checked_sub(1)
+            .and_then(|i| self.files.get_mut(i))
+            .and_then(Option::take);
+        self.push_data(if closed.is_some() { 0 } else { IOR_FILE_IO })
+    }
+
+    pub(crate) fn read_file(&mut self) -> VMSuccess {
+        let id = self.pop_data()?;
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        // no more than fits in memory can be read, and the buffer is
+        // checked first so that a bad address doesn't lose what was read
+        let len = len.min(self.memory_size());
+        self.destination(addr, len)?;
+        let mut buf = vec![0; len as usize];
+        let mut count = 0;
+        let mut result = 0;
+        match self.file(id) {
+            Some(file) => {
+                while count < buf.len() {
+                    match file.read(&mut buf[count..]) {
+                        Ok(0) => break,
+                        Ok(n) => count += n,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                        Err(e) => {
+                            result = ior(e);
+                            break;
+                        }
+                    }
+                }
+            }
+            None => result = IOR_FILE_IO,
+        }
+        self.bytes_mut(addr, count as Cell)?
+            .copy_from_slice(&buf[..count]);
+        self.push_data(count as Cell)?;
+        self.push_data(result)
+    }
+
+    pub(crate) fn read_line(&mut self) -> VMSuccess {
+        let id = self.pop_data()?;
+        let max = self.pop_data()?;
+        let addr = self.pop_data()?;
+        let max = max.min(self.memory.len() as Cell);
+        // read enough to see the terminator of a line of the maximum
+        // length, then seek back over anything belonging to the next line
+        let mut buf = vec![0; max as usize + 2];
+        let mut count = 0;
+        let file = match self.file(id) {
+            Some(file) => file,
+            None => {
+                self.push_data(0)?;
+                self.push_data(0)?;
+                return self.push_data(IOR_FILE_IO);
+            }
+        };
+        let mut result = 0;
+        while count < buf.len() {
+            match file.read(&mut buf[count..]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    count += n;
+                    if buf[..count].contains(&b'\n') {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    result = ior(e);
+                    break;
+                }
+            }
+        }
+        let search = count.min(max as usize + 1);
+        let (mut line_len, consumed) = match buf[..search].iter().position(|&b| b == b'\n') {
+            Some(i) => (i, i + 1),
+            None => {
+                let n = count.min(max as usize);
+                (n, n)
+            }
+        };
+        if line_len > 0 && buf[line_len - 1] == b'\r' && consumed > line_len {
+            line_len -= 1;
+        }
+        if result == 0 && consumed < count {
+            let back = -((count - consumed) as i64);
+            if let Err(e) = file.seek(SeekFrom::Current(back)) {
+                result = ior(e);
+            }
+        }
+        self.bytes_mut(addr, line_len as Cell)?
+            .copy_from_slice(&buf[..line_len]);
+        self.push_data(line_len as Cell)?;
+        self.push_data(if count > 0 { 1 } else { 0 })?;
+        self.push_data(result)
+    }
+
+    fn write_to_file(&mut self, newline: bool) -> VMSuccess {
+        let id = self.pop_data()?;
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        let mut data = self.read_bytes(addr, len)?.to_vec();
+        if newline {
+            data.push(b'\n');
+        }
+        let result = match self.file(id) {
+            Some(file) => match file.write_all(&data) {
+                Ok(()) => 0,
+                Err(e) => ior(e),
+            },
+            None => IOR_FILE_IO,
+        };
+        self.push_data(result)
+    }
+
+    pub(crate) fn write_file(&mut self) -> VMSuccess {
+        self.write_to_file(false)
+    }
+
+    pub(crate) fn write_line(&mut self) -> VMSuccess {
+        self.write_to_file(true)
+    }
+
+    fn push_position(&mut self, position: Option<std::io::Result<u64>>) -> VMSuccess {
+        let (value, result) = match position {
+            Some(Ok(n)) => (n, 0),
+            Some(Err(e)) => (0, ior(e)),
+            None => (0, IOR_FILE_IO),
+        };
+        let (low, high) = split_double(value);
+        self.push_data(low)?;
+        self.push_data(high)?;
+        self.push_data(result)
+    }
+
+    pub(crate) fn file_size(&mut self) -> VMSuccess {
+        let id = self.pop_data()?;
+        let size = self.file(id).map(|f| f.metadata().map(|m| m.len()));
+        self.push_position(size)
+    }
+
+    pub(crate) fn file_position(&mut self) -> VMSuccess {
+        let id = self.pop_data()?;
+        let position = self.file(id).map(|f| f.stream_position());
+        self.push_position(position)
+    }
+
+    pub(crate) fn reposition_file(&mut self) -> VMSuccess {
+        let id = self.pop_data()?;
+        let high = self.pop_data()?;
+        let low = self.pop_data()?;
+        let result = match (self.file(id), join_double(low, high)) {
+            (Some(file), Some(position)) => match file.seek(SeekFrom::Start(position)) {
+                Ok(_) => 0,
+                Err(e) => ior(e),
+            },
+            _ => IOR_FILE_IO,
+        };
+        self.push_data(result)
+    }
+
+    fn pop_file_name(&mut self) -> VMResult<String> {
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        self.read_string(addr, len)
+    }
+
+    pub(crate) fn delete_file(&mut self) -> VMSuccess {
+        let name = self.pop_file_name()?;
+        if !self.file_access {
+            return self.push_data(IOR_FILE_IO);
+        }
+        let result = match std::fs::remove_file(name) {
+            Ok(()) => 0,
+            Err(e) => ior(e),
+        };
+        self.push_data(result)
+    }
+
+    pub(crate) fn rename_file(&mut self) -> VMSuccess {
+        let new_name = self.pop_file_name()?;
+        let old_name = self.pop_file_name()?;
+        if !self.file_access {
+            return self.push_data(IOR_FILE_IO);
+        }
+        let result = match std::fs::rename(old_name, new_name) {
+            Ok(()) => 0,
+            Err(e) => ior(e),
+        };
+        self.push_data(result)
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+use crate::{Cell, SignedCell, VMErrorKind, VMResult, VMSuccess, ADDR_HERE, CELL_SIZE, VM};
+
+// used when no memory limit has been set, so that the heap has a top
+const DEFAULT_MEMORY_SIZE: Cell = 1 << 20;
+
+pub(crate) const IOR_ALLOCATE: Cell = -59i32 as Cell;
+pub(crate) const IOR_FREE: Cell = -60i32 as Cell;
+pub(crate) const IOR_RESIZE: Cell = -61i32 as Cell;
+
+// The heap occupies the top of memory and grows downwards towards the
+// dictionary. Block sizes are rounded up to whole cells, and freed blocks
+// are kept in a free list (coalesced with their neighbours) for reuse. The
+// heap's bytes, from its floor to the top of memory, are kept apart from
+// the dictionary's, so only memory that has been allocated takes space.
+#[derive(Default)]
+pub(crate) struct Heap {
+    pub(crate) floor: Option<Cell>,
+    pub(crate) memory: Vec<u8>,
+    allocated: BTreeMap<Cell, Cell>,
+    free: BTreeMap<Cell, Cell>,
+}
+
+// the size of block needed for size bytes, or None if rounding it up to a
+// whole number of cells would overflow
+fn block_size(size: Cell) -> Option<Cell> {
+    size.max(1)
+        .checked_add(CELL_SIZE - 1)
+        .map(|size| size & !(CELL_SIZE - 1))
+}
+
+impl VM {
+    pub(crate) fn memory_size(&self) -> Cell {
+        self.limits.max_memory.unwrap_or(DEFAULT_MEMORY_SIZE)
+    }
+
+    // lowest address used by the heap, i.e. the limit for the dictionary
+    pub(crate) fn heap_floor(&self) -> Cell {
+        self.heap.floor.unwrap_or_else(|| self.memory_size())
+    }
+
+    pub(crate) fn unused(&self) -> VMResult<Cell> {
+        let here = self.read_cell(ADDR_HERE)?;
+        Ok(self.heap_floor().saturating_sub(here))
+    }
+
+    pub(crate) fn allot(&mut self, n: SignedCell) -> VMSuccess {
+        let here = self.read_cell(ADDR_HERE)?;
+        let new_here = here.wrapping_add(n as Cell);
+        if n > 0 {
+            if new_here < here || new_here > self.heap_floor() {
+                return Err(VMErrorKind::MemoryLimitExceeded);
+            }
+            if new_here as usize > self.memory.len() {
+                self.memory.resize(new_here as usize, 0);
+            }
+        } else if new_here > here {
+            return Err(VMErrorKind::IllegalAddress(new_here));
+        }
+        self.write_cell(ADDR_HERE, new_here)
+    }
+
+    // returns None (rather than an error) if there is no room, because
+    // that is reported to Forth code as an ior
+    pub(crate) fn allocate(&mut self, size: Cell) -> VMResult<Option<Cell>> {
+        let size = match block_size(size) {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        let fit = self
+            .heap
+            .free
+            .iter()
+            .find(|(_, &free_size)| free_size >= size)
+            .map(|(&addr, &free_size)| (addr, free_size));
+        let addr = match fit {
+            Some((addr, free_size)) => {
+                self.heap.free.remove(&addr);
+                if free_size > size {
+                    self.heap.free.insert(addr + size, free_size - size);
+                }
+                addr
+            }
+            None => {
+                let here = self.read_cell(ADDR_HERE)?;
+                let addr = match self.heap_floor().checked_sub(size) {
+                    Some(addr) if addr >= here => addr,
+                    _ => return Ok(None),
+                };
+                let grown = (self.heap_floor() - addr) as usize;
+                self.heap.memory.splice(0..0, std::iter::repeat_n(0, grown));
+                // anything stored beyond HERE in the space now taken is lost
+                self.memory.truncate(addr as usize);
+                self.heap.floor = Some(addr);
+                addr
+            }
+        };
+        self.heap.allocated.insert(addr, size);
+        Ok(Some(addr))
+    }
+
+    fn release(&mut self, mut addr: Cell, mut size: Cell) {
+        if let Some((&prev, &prev_size)) = self.heap.free.range(..addr).next_back() {
+            if prev + prev_size == addr {
+                self.heap.free.remove(&prev);
+                addr = prev;
+                size += prev_size;
+            }
+        }
+        if let Some(next_size) = self.heap.free.remove(&(addr + size)) {
+            size += next_size;
+        }
+        if addr == self.heap_floor() {
+            self.heap.memory.drain(..size as usize);
+            self.heap.floor = Some(addr + size);
+        } else {
+            self.heap.free.insert(addr, size);
+        }
+    }
+
+    pub(crate) fn free(&mut self, addr: Cell) -> bool {
+        match self.heap.allocated.remove(&addr) {
+            Some(size) => {
+                self.release(addr, size);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn resize(&mut self, addr: Cell, size: Cell) -> VMResult<Option<Cell>> {
+        let old_size = match self.heap.allocated.get(&addr) {
+            Some(&old_size) => old_size,
+            None => return Ok(None),
+        };
+        let size = match block_size(size) {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        if size <= old_size {
+            if size < old_size {
+                self.heap.allocated.insert(addr, size);
+                self.release(addr + size, old_size - size);
+            }
+            return Ok(Some(addr));
+        }
+        let new_addr = match self.allocate(size)? {
+            Some(new_addr) => new_addr,
+            None => return Ok(None),
+        };
+        let floor = self.heap_floor();
+        let (from, to) = ((addr - floor) as usize, (new_addr - floor) as usize);
+        self.heap
+            .memory
+            .copy_within(from..(from + old_size as usize), to);
+        self.free(addr);
+        Ok(Some(new_addr))
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use crate::{
+    align_addr, Cell, Op, VMResult, VMSuccess, ADDR_HERE, ADDR_LATEST, CELL_SIZE, INLINE_FLAG, VM,
+};
+
+// Compiling a reference to a short colon definition copies its body into
+// the definition being compiled, instead of compiling a call to it. Words
+// marked `inline` are always copied, if they can be. Others are copied if
+// their body has at most INLINE_THRESHOLD cells, and it only uses
+// primitives and never takes anything off the return stack that it didn't
+// put there itself. A word that did either of those could be relying on
+// having a return address of its own, which a copy of it doesn't. The
+// primitives that can do that are excluded too: execute, which might run
+// anything, activate, which takes the return address of the word using it,
+// and reset, which empties the return stack.
+pub(crate) const INLINE_THRESHOLD: Cell = 4;
+
+// the largest body that is copied even when the word is marked `inline`
+const MAX_INLINE: Cell = 256;
+
+impl VM {
+    /// Turn inlining of short colon definitions on or off. It's on by
+    /// default; with it off, every reference to a word compiles a call.
+    pub fn set_inlining(&mut self, enabled: bool) {
+        self.inlining = enabled;
+    }
+
+    // make the latest word one that is always inlined
+    pub(crate) fn inline(&mut self) -> VMSuccess {
+        let header_addr = self.read_cell(ADDR_LATEST)?;
+        let byte = self.read_u8(header_addr.wrapping_add(CELL_SIZE))?;
+        let byte = byte ^ INLINE_FLAG;
+        self.write_u8(header_addr.wrapping_add(CELL_SIZE), byte)
+    }
+
+    // compile a reference to the word xt whose header has the given flags
+    pub(crate) fn compile_reference(&mut self, xt: Cell, flags: u8) -> VMSuccess {
+        let here = self.read_cell(ADDR_HERE)?;
+        // the word after ' is its operand, so it must stay an xt
+        let after_tick =
+            self.read_cell(here.wrapping_sub(CELL_SIZE)).ok() == Some(self.peephole.words.tick);
+        if self.inlining && !after_tick {
+            let explicit = flags & INLINE_FLAG != 0;
+            if let Some(body) = self.inlined_body(xt, explicit)? {
+                for cell in body {
+                    self.write_cell_here(cell)?;
+                }
+                return Ok(());
+            }
+        }
+        self.write_cell_here(xt)
+    }
+
+    // the body of the colon definition xt, ready to be copied into another
+    // definition, or None if it shouldn't be inlined
+    fn inlined_body(&self, xt: Cell, explicit: bool) -> VMResult<Option<Vec<Cell>>> {
+        if self.read_u8(xt)? != Op::DoColonDef.into() {
+            return Ok(None);
+        }
+        let w = &self.peephole.words;
+        let limit = if explicit {
+            MAX_INLINE
+        } else {
+            INLINE_THRESHOLD
+        };
+        let start = align_addr(xt.wrapping_add(1));
+        let Some((instructions, end)) = self.body_instructions(start, limit)? else {
+            return Ok(None);
+        };
+        if !explicit && !self.balances_return_stack(&instructions)? {
+            return Ok(None);
+        }
+        for &addr in &instructions {
+            let cell = self.read_cell(addr)?;
+            if cell == w.branch || cell == w.branch_if_zero {
+                let target = addr.wrapping_add(self.read_cell(addr.wrapping_add(CELL_SIZE))?);
+                if target < start {
+                    return Ok(None);
+                }
+            }
+            let primitive = self
+                .read_u8(cell)
+                .is_ok_and(|op| op != Op::DoColonDef.into());
+            let uses_return_address = [w.execute, w.activate, w.reset].contains(&cell);
+            if !explicit && (!primitive || uses_return_address) {
+                return Ok(None);
+            }
+        }
+
+        // where each instruction goes in the copy, allowing for an exit
+        // before the end becoming a branch to the end, which is a cell
+        // longer
+        let mut moved = HashMap::new();
+        let mut len = 0;
+        for (i, &addr) in instructions.iter().enumerate() {
+            moved.insert(addr, len);
+            let next = instructions.get(i + 1).copied().unwrap_or(end);
+            len += match self.read_cell(addr)? {
+                cell if cell == w.exit => 2 * CELL_SIZE,
+                _ => next - addr,
+            };
+        }
+        moved.insert(end, len);
+
+        let mut body = Vec::new();
+        for (i, &addr) in instructions.iter().enumerate() {
+            let cell = self.read_cell(addr)?;
+            let offset = |target: Cell| moved.get(&target).map(|&to| to.wrapping_sub(moved[&addr]));
+            if cell == w.exit {
+                body.extend([w.branch, len - moved[&addr]]);
+            } else if cell == w.branch || cell == w.branch_if_zero {
+                let target = addr.wrapping_add(self.read_cell(addr.wrapping_add(CELL_SIZE))?);
+                match offset(target) {
+                    Some(offs) => body.extend([cell, offs]),
+                    // a branch into the middle of an instruction
+                    None => return Ok(None),
+                }
+            } else {
+                let next = instructions.get(i + 1).copied().unwrap_or(end);
+                for a in (addr..next).step_by(CELL_SIZE as usize) {
+                    body.push(self.read_cell(a)?);
+                }
+            }
+        }
+        Ok(Some(body))
+    }
+
+    // whether a call to the colon definition xt can become a branch to its
+    // body, which leaves it without a return address of its own: it and
+    // every word it calls must only take off the return stack what they
+    // put there themselves, and mustn't use execute, activate or reset.
+    // The words in checked are taken to be fine, which stops recursion.
+    pub(crate) fn returns_normally(&self, xt: Cell, checked: &mut Vec<Cell>) -> VMResult<bool> {
+        if checked.contains(&xt) {
+            return Ok(true);
+        }
+        checked.push(xt);
+        let w = &self.peephole.words;
+        let start = align_addr(xt.wrapping_add(1));
+        let Some((instructions, _)) = self.body_instructions(start, Cell::MAX)? else {
+            return Ok(false);
+        };
+        if !self.balances_return_stack(&instructions)? {
+            return Ok(false);
+        }
+        for addr in instructions {
+            let cell = self.read_cell(addr)?;
+            if [w.execute, w.activate, w.reset].contains(&cell) {
+                return Ok(false);
+            }
+            let colon = self.read_u8(cell).ok() == Some(Op::DoColonDef.into());
+            if colon && !self.returns_normally(cell, checked)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // the instructions of the colon definition body at start, up to the
+    // exit that ends it, which is the first one that no branch jumps past,
+    // and the address of that exit; None if the body is longer than limit
+    // cells or runs off the end of memory
+    fn body_instructions(&self, start: Cell, limit: Cell) -> VMResult<Option<(Vec<Cell>, Cell)>> {
+        let w = &self.peephole.words;
+        let memory_end = self.memory.len() as Cell;
+        let mut instructions = Vec::new();
+        let mut furthest_target = start;
+        let mut addr = start;
+        loop {
+            if addr.saturating_sub(start) / CELL_SIZE > limit || addr >= memory_end {
+                return Ok(None);
+            }
+            let cell = self.read_cell(addr)?;
+            if cell == w.exit && addr >= furthest_target {
+                return Ok(Some((instructions, addr)));
+            }
+            if cell == w.branch || cell == w.branch_if_zero {
+                let target = addr.wrapping_add(self.read_cell(addr.wrapping_add(CELL_SIZE))?);
+                furthest_target = furthest_target.max(target);
+            }
+            instructions.push(addr);
+            addr = addr.saturating_add(self.instruction_cells(addr, memory_end)? * CELL_SIZE);
+        }
+    }
+
+    // whether the instructions only take off the return stack what they
+    // put there themselves
+    fn balances_return_stack(&self, instructions: &[Cell]) -> VMResult<bool> {
+        let w = &self.peephole.words;
+        let mut depth = 0i32;
+        for &addr in instructions {
+            let cell = self.read_cell(addr)?;
+            if cell == w.to_r {
+                depth += 1;
+            } else if cell == w.from_r {
+                depth -= 1;
+                if depth < 0 {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(depth == 0)
+    }
+}
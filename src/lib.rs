@@ -0,0 +1,1422 @@
+use num_enum::{FromPrimitive, IntoPrimitive};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, IsTerminal, Read, Write};
+use std::iter::once;
+use std::path::Path;
+
+mod arith;
+mod blocks;
+mod builtins;
+mod coverage;
+mod editor;
+mod files;
+mod heap;
+mod inline;
+mod peephole;
+mod profile;
+mod source;
+mod strings;
+mod tasks;
+mod tester;
+mod xchar;
+
+use arith::{arshift, bit_clear, bit_set, bit_test, flag, lshift, rshift, Rounding};
+use blocks::Blocks;
+use coverage::Coverage;
+use editor::LineEditor;
+use heap::{Heap, IOR_ALLOCATE, IOR_FREE, IOR_RESIZE};
+use peephole::Peephole;
+use profile::Profiler;
+use source::Source;
+use strings::CopyOrder;
+use tasks::Tasks;
+use tester::Tester;
+
+/// A cell, the VM's unit of data: the items on the stacks, addresses, and
+/// the words of compiled code are all cells. Cells are 32 bits, or 64 with
+/// the `cell64` feature.
+#[cfg(not(feature = "cell64"))]
+pub type Cell = u32;
+#[cfg(feature = "cell64")]
+pub type Cell = u64;
+
+/// A cell taken as a signed number.
+#[cfg(not(feature = "cell64"))]
+pub type SignedCell = i32;
+#[cfg(feature = "cell64")]
+pub type SignedCell = i64;
+
+/// The size of a cell in bytes.
+pub const CELL_SIZE: Cell = std::mem::size_of::<Cell>() as Cell;
+
+const ADDR_LATEST: Cell = 0;
+const ADDR_BASE: Cell = CELL_SIZE;
+const ADDR_STATE: Cell = 2 * CELL_SIZE;
+const ADDR_HERE: Cell = 3 * CELL_SIZE;
+const ADDR_WORD_BUFFER: Cell = 4 * CELL_SIZE;
+const INITIAL_HERE: Cell = ADDR_WORD_BUFFER + 32;
+
+const MAX_EXTEND: Cell = 64;
+
+const DEFAULT_STACK_SIZE: usize = 4096;
+
+const HIDDEN_FLAG: u8 = 32;
+const IMMEDIATE_FLAG: u8 = 64;
+const LENGTH_MASK: u8 = 31;
+const INLINE_FLAG: u8 = 128;
+
+#[derive(FromPrimitive, IntoPrimitive, Copy, Clone)]
+#[repr(u8)]
+enum Op {
+    DoColonDef = 0,
+    Dup,
+    Drop,
+    Swap,
+    Depth,
+    ToR,
+    FromR,
+    Fetch,
+    CFetch,
+    Store,
+    CStore,
+    Align,
+    Add,
+    Subtract,
+    Multiply,
+    DivMod,
+    Equals,
+    LessThan,
+    GreaterThan,
+    And,
+    Or,
+    Xor,
+    Invert,
+    Lit,
+    LitString,
+    Key,
+    Word,
+    Emit,
+    Find,
+    Number,
+    ToCFA,
+    LBracket,
+    RBracket,
+    Create,
+    Comma,
+    CComma,
+    Immediate,
+    Hidden,
+    Tick,
+    Execute,
+    Branch,
+    BranchIfZero,
+    Exit,
+    Reset,
+    Interpret,
+    Allot,
+    Unused,
+    Allocate,
+    Free,
+    Resize,
+    OpenFile,
+    CreateFile,
+    CloseFile,
+    ReadFile,
+    ReadLine,
+    WriteFile,
+    WriteLine,
+    FileSize,
+    FilePosition,
+    RepositionFile,
+    DeleteFile,
+    RenameFile,
+    Block,
+    Buffer,
+    Update,
+    SaveBuffers,
+    Flush,
+    Load,
+    TestStart,
+    TestResults,
+    TestEnd,
+    Optimise,
+    LitAdd,
+    DupBranchIfZero,
+    Nip,
+    Over,
+    TwoDup,
+    Inline,
+    NewTask,
+    Activate,
+    Pause,
+    Stop,
+    Wake,
+    UserArea,
+    XCharFetch,
+    XCharStore,
+    XCharSize,
+    XEmit,
+    XKey,
+    Compare,
+    Search,
+    SlashString,
+    MinusTrailing,
+    Blank,
+    CMove,
+    CMoveUp,
+    Move,
+    Fill,
+    Erase,
+    Count,
+    Type,
+    Source,
+    Refill,
+    Parse,
+    ParseName,
+    Evaluate,
+    ULessThan,
+    UGreaterThan,
+    LShift,
+    RShift,
+    ARShift,
+    TwoStar,
+    TwoSlash,
+    Within,
+    Min,
+    Max,
+    Abs,
+    BitTest,
+    BitSet,
+    BitClear,
+    SmRem,
+    FmMod,
+    UmMod,
+    StarSlash,
+    StarSlashMod,
+    #[num_enum(default)]
+    Unknown,
+}
+
+/// The reason execution failed, with whatever details are specific to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VMErrorKind {
+    IllegalAddress(Cell),
+    UnknownOpcode { opcode: u8, addr: Cell },
+    DataStackUnderflow,
+    ReturnStackUnderflow,
+    UnalignedAccess(Cell),
+    MathError,
+    IOError,
+    UnknownWord(String),
+    Terminated,
+    FuelExhausted,
+    MemoryLimitExceeded,
+    DataStackOverflow(String),
+    ReturnStackOverflow(String),
+    InvalidBlock(Cell),
+    InvalidTask(Cell),
+}
+
+impl fmt::Display for VMErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VMErrorKind::IllegalAddress(addr) => write!(f, "illegal address {:#x}", addr),
+            VMErrorKind::UnknownOpcode { opcode, addr } => {
+                write!(f, "unknown opcode {} at {:#x}", opcode, addr)
+            }
+            VMErrorKind::DataStackUnderflow => write!(f, "data stack underflow"),
+            VMErrorKind::ReturnStackUnderflow => write!(f, "return stack underflow"),
+            VMErrorKind::UnalignedAccess(addr) => {
+                write!(f, "unaligned memory access at {:#x}", addr)
+            }
+            VMErrorKind::MathError => write!(f, "math error"),
+            VMErrorKind::IOError => write!(f, "i/o error"),
+            VMErrorKind::UnknownWord(s) => write!(f, "unknown word {}", s),
+            VMErrorKind::Terminated => write!(f, "input terminated"),
+            VMErrorKind::FuelExhausted => write!(f, "instruction limit exceeded"),
+            VMErrorKind::MemoryLimitExceeded => write!(f, "memory limit exceeded"),
+            VMErrorKind::DataStackOverflow(s) => write!(f, "data stack overflow in {}", s),
+            VMErrorKind::ReturnStackOverflow(s) => write!(f, "return stack overflow in {}", s),
+            VMErrorKind::InvalidBlock(block) => write!(f, "invalid block number {}", block),
+            VMErrorKind::InvalidTask(addr) => write!(f, "no task at {:#x}", addr),
+        }
+    }
+}
+
+/// An error raised while running, along with the state of the VM at the
+/// time it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VMError {
+    pub kind: VMErrorKind,
+    pub pc: Cell,
+    pub data_depth: usize,
+    pub return_depth: usize,
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (pc {:#x}, data stack depth {}, return stack depth {})",
+            self.kind, self.pc, self.data_depth, self.return_depth
+        )
+    }
+}
+
+impl std::error::Error for VMError {}
+
+fn align_addr(addr: Cell) -> Cell {
+    addr.wrapping_add(CELL_SIZE - 1) & !(CELL_SIZE - 1)
+}
+
+fn digit_val(digit: char) -> Cell {
+    if digit >= '0' && digit <= '9' {
+        return (digit as Cell).wrapping_sub('0' as Cell);
+    }
+    (digit as Cell).wrapping_sub('a' as Cell).wrapping_add(10)
+}
+
+type VMResult<T> = Result<T, VMErrorKind>;
+type VMSuccess = VMResult<()>;
+
+type Input = Box<dyn Iterator<Item = std::io::Result<u8>>>;
+
+// a line in one of the source files (indexing VM::source_names)
+#[derive(Clone, Copy)]
+struct Location {
+    file: usize,
+    line: u32,
+}
+
+// read a source file as if it were typed on a single line, so that it is
+// loaded without prompting
+fn file_input(f: File) -> Input {
+    Box::new(
+        BufReader::new(f)
+            .bytes()
+            .map(|b| if matches!(b, Ok(13)) { Ok(32) } else { b })
+            .chain(once(Ok(b'\n'))),
+    )
+}
+
+/// Resource limits for running untrusted code; `None` means unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub max_memory: Option<Cell>,
+    pub max_data_stack: Option<usize>,
+    pub max_return_stack: Option<usize>,
+}
+
+impl Default for Limits {
+    // the stacks are always bounded so that runaway recursion is
+    // reported as an error rather than exhausting the host's memory
+    fn default() -> Self {
+        Self {
+            max_steps: None,
+            max_memory: None,
+            max_data_stack: Some(DEFAULT_STACK_SIZE),
+            max_return_stack: Some(DEFAULT_STACK_SIZE),
+        }
+    }
+}
+
+pub struct VM {
+    memory: Vec<u8>,
+    data_stack: Vec<Cell>,
+    return_stack: Vec<Cell>,
+    pc: Cell,
+    entry: Cell,
+    lit: Cell,
+    input: Input,
+    queued: VecDeque<(String, Input)>,
+    stdin: Option<Input>,
+    terminal_started: bool,
+    editor: Option<LineEditor>,
+    source: Source,
+    sources: Vec<Source>,
+    tib: Cell,
+    addr_to_in: Cell,
+    source_names: Vec<String>,
+    location: Option<Location>,
+    nested_interpreter: Cell,
+    running: bool,
+    line: bool,
+    errors: Vec<VMError>,
+    limits: Limits,
+    steps: u64,
+    heap: Heap,
+    files: Vec<Option<File>>,
+    file_access: bool,
+    blocks: Blocks,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    tester: Tester,
+    interactive: bool,
+    output: Box<dyn Write>,
+    peephole: Peephole,
+    inlining: bool,
+    tasks: Tasks,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    /// Create a VM that reads the prelude followed by standard input. Call
+    /// `init` before running it.
+    pub fn new() -> Self {
+        // use the line editor when a person is typing, otherwise (or if
+        // the editor can't be set up) read raw bytes so that piped input
+        // behaves as it always has
+        let stdin = std::io::stdin();
+        if stdin.is_terminal() {
+            if let Ok(editor) = LineEditor::new() {
+                return Self::with_terminal(Some(editor), None);
+            }
+        }
+        Self::with_terminal(None, Some(Box::new(stdin.lock().bytes())))
+    }
+
+    /// Create a VM that reads the prelude followed by `input` in place of
+    /// standard input, e.g. for running Forth code from a test.
+    pub fn with_input(input: impl Read + 'static) -> Self {
+        Self::with_terminal(None, Some(Box::new(BufReader::new(input).bytes())))
+    }
+
+    fn with_terminal(editor: Option<LineEditor>, stdin: Option<Input>) -> Self {
+        let f = File::open("prelude.f").expect("Could not open prelude");
+        let queued = VecDeque::from([("prelude.f".to_owned(), file_input(f))]);
+        let mut me = Self {
+            memory: vec![0; INITIAL_HERE as usize],
+            data_stack: Vec::new(),
+            return_stack: Vec::new(),
+            pc: 0,
+            entry: 0,
+            lit: 0,
+            input: Box::new(std::iter::empty()),
+            queued,
+            stdin,
+            terminal_started: false,
+            editor,
+            source: Source::input(),
+            sources: Vec::new(),
+            tib: 0,
+            addr_to_in: 0,
+            source_names: Vec::new(),
+            location: None,
+            nested_interpreter: 0,
+            running: true,
+            line: false,
+            errors: Vec::new(),
+            limits: Limits::default(),
+            steps: 0,
+            heap: Heap::default(),
+            files: Vec::new(),
+            file_access: true,
+            blocks: Blocks::default(),
+            profiler: None,
+            coverage: None,
+            tester: Tester::default(),
+            interactive: true,
+            output: Box::new(std::io::stdout()),
+            peephole: Peephole::default(),
+            inlining: true,
+            tasks: Tasks::default(),
+        };
+        me.write_cell(ADDR_BASE, 10).unwrap();
+        me.write_cell(ADDR_HERE, INITIAL_HERE).unwrap();
+        me
+    }
+
+    fn push_data(&mut self, data: Cell) -> VMSuccess {
+        if let Some(max) = self.limits.max_data_stack {
+            if self.data_stack.len() >= max {
+                return Err(VMErrorKind::DataStackOverflow(self.current_word_name()));
+            }
+        }
+        self.data_stack.push(data);
+        Ok(())
+    }
+
+    fn pop_data(&mut self) -> VMResult<Cell> {
+        self.data_stack.pop().ok_or(VMErrorKind::DataStackUnderflow)
+    }
+
+    fn push_return(&mut self, addr: Cell) -> VMSuccess {
+        if let Some(max) = self.limits.max_return_stack {
+            if self.return_stack.len() >= max {
+                return Err(VMErrorKind::ReturnStackOverflow(self.current_word_name()));
+            }
+        }
+        self.return_stack.push(addr);
+        Ok(())
+    }
+
+    fn pop_return(&mut self) -> VMResult<Cell> {
+        self.return_stack
+            .pop()
+            .ok_or(VMErrorKind::ReturnStackUnderflow)
+    }
+
+    fn align(&mut self) -> VMSuccess {
+        let mut here = self.read_cell(ADDR_HERE)?;
+        here = align_addr(here);
+        self.write_cell(ADDR_HERE, here)
+    }
+
+    // The dictionary's bytes are kept from address 0 up, and the heap's
+    // from its floor up to the top of memory, so the unused memory between
+    // them takes no space. These give the bytes that hold addr and the
+    // address of the first of them.
+    fn region(&self, addr: Cell) -> (&[u8], Cell) {
+        match self.heap.floor {
+            Some(floor) if addr >= floor => (&self.heap.memory, floor),
+            _ => (&self.memory, 0),
+        }
+    }
+
+    fn region_mut(&mut self, addr: Cell) -> (&mut [u8], Cell) {
+        match self.heap.floor {
+            Some(floor) if addr >= floor => (&mut self.heap.memory, floor),
+            _ => (&mut self.memory, 0),
+        }
+    }
+
+    fn read_u8(&self, addr: Cell) -> VMResult<u8> {
+        let (bytes, base) = self.region(addr);
+        bytes
+            .get((addr - base) as usize)
+            .copied()
+            .ok_or(VMErrorKind::IllegalAddress(addr))
+    }
+
+    fn read_bytes(&self, addr: Cell, len: Cell) -> VMResult<&[u8]> {
+        let (bytes, base) = self.region(addr);
+        let start = (addr - base) as usize;
+        let end = start
+            .checked_add(len as usize)
+            .ok_or(VMErrorKind::IllegalAddress(addr))?;
+        bytes
+            .get(start..end)
+            .ok_or(VMErrorKind::IllegalAddress(addr))
+    }
+
+    fn bytes_mut(&mut self, addr: Cell, len: Cell) -> VMResult<&mut [u8]> {
+        let (bytes, base) = self.region_mut(addr);
+        let start = (addr - base) as usize;
+        let end = start
+            .checked_add(len as usize)
+            .ok_or(VMErrorKind::IllegalAddress(addr))?;
+        bytes
+            .get_mut(start..end)
+            .ok_or(VMErrorKind::IllegalAddress(addr))
+    }
+
+    fn read_string(&self, addr: Cell, len: Cell) -> VMResult<String> {
+        Ok(String::from_utf8_lossy(self.read_bytes(addr, len)?).into_owned())
+    }
+
+    // make sure size bytes starting at addr exist, growing memory if needed
+    fn extend_memory(&mut self, addr: Cell, size: usize) -> VMSuccess {
+        let len = (addr as usize).saturating_add(size);
+        if let Some(floor) = self.heap.floor {
+            if addr >= floor {
+                // the heap's memory is made by allocating it
+                return Ok(());
+            }
+            if len > floor as usize {
+                return Err(VMErrorKind::IllegalAddress(addr));
+            }
+        }
+        if len > self.memory.len() + MAX_EXTEND as usize {
+            return Err(VMErrorKind::IllegalAddress(addr));
+        }
+        if let Some(max) = self.limits.max_memory {
+            if len > max as usize {
+                return Err(VMErrorKind::MemoryLimitExceeded);
+            }
+        }
+        if len > self.memory.len() {
+            self.memory.resize(len, 0);
+        }
+        Ok(())
+    }
+
+    fn write_u8(&mut self, addr: Cell, data: u8) -> VMSuccess {
+        self.extend_memory(addr, 1)?;
+        self.bytes_mut(addr, 1)?[0] = data;
+        Ok(())
+    }
+
+    fn write_u8_here(&mut self, data: u8) -> VMSuccess {
+        let here = self.read_cell(ADDR_HERE)?;
+        if here >= self.heap_floor() {
+            return Err(VMErrorKind::MemoryLimitExceeded);
+        }
+        self.write_u8(here, data)?;
+        self.write_cell(ADDR_HERE, here + 1)
+    }
+
+    fn read_cell(&self, addr: Cell) -> VMResult<Cell> {
+        if addr % CELL_SIZE != 0 {
+            return Err(VMErrorKind::UnalignedAccess(addr));
+        }
+        let bytes = self.read_bytes(addr, CELL_SIZE)?;
+        Ok(Cell::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn write_cell(&mut self, addr: Cell, data: Cell) -> VMSuccess {
+        if addr % CELL_SIZE != 0 {
+            return Err(VMErrorKind::UnalignedAccess(addr));
+        }
+        self.extend_memory(addr, CELL_SIZE as usize)?;
+        self.bytes_mut(addr, CELL_SIZE)?
+            .copy_from_slice(&data.to_le_bytes());
+        Ok(())
+    }
+
+    fn write_cell_here(&mut self, data: Cell) -> VMSuccess {
+        let here = self.read_cell(ADDR_HERE)?;
+        if here > self.heap_floor().saturating_sub(CELL_SIZE) {
+            return Err(VMErrorKind::MemoryLimitExceeded);
+        }
+        if let (Some(coverage), Some(location)) = (&mut self.coverage, self.location) {
+            coverage.compiled(here, location);
+        }
+        self.write_cell(here, data)?;
+        self.write_cell(ADDR_HERE, here + CELL_SIZE)
+    }
+
+    fn buffer_word(&mut self, word: &str) -> VMSuccess {
+        let bytes = word.as_bytes();
+        let n = 32.min(bytes.len());
+        self.bytes_mut(ADDR_WORD_BUFFER, n as Cell)?
+            .copy_from_slice(&bytes[..n]);
+        self.push_data(ADDR_WORD_BUFFER)?;
+        self.push_data(n as Cell)
+    }
+
+    fn find_word(&self, addr: Cell, len: u8) -> VMResult<Cell> {
+        let mut search_addr = self.read_cell(ADDR_LATEST)?;
+        while search_addr != 0 {
+            if self.read_u8(search_addr.wrapping_add(CELL_SIZE))? & (LENGTH_MASK | HIDDEN_FLAG)
+                == len
+            {
+                let mut found = true;
+                for i in 0..len as Cell {
+                    if self.read_u8(search_addr.wrapping_add(CELL_SIZE + 1 + i))?
+                        != self.read_u8(addr.wrapping_add(i))?
+                    {
+                        found = false;
+                        break;
+                    }
+                }
+                if found {
+                    return Ok(search_addr);
+                }
+            }
+            search_addr = self.next_header(search_addr)?;
+        }
+        Ok(0)
+    }
+
+    fn find(&mut self) -> VMSuccess {
+        let len = self.pop_data()? as u8;
+        let addr = self.pop_data()?;
+        self.push_data(self.find_word(addr, len)?)
+    }
+
+    fn number(&mut self) -> VMSuccess {
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        let (value, error) = self.parse_number(addr, len)?;
+        self.push_data(value)?;
+        self.push_data(error)
+    }
+
+    fn parse_number(&self, addr: Cell, len: Cell) -> VMResult<(Cell, Cell)> {
+        let base = self.read_cell(ADDR_BASE)?;
+        let mut offs = 0;
+        let sym = self.read_u8(addr)?;
+        let sign = if sym as char == '-' && len > 1 {
+            offs += 1;
+            -1
+        } else {
+            1
+        };
+        let mut result: Cell = 0;
+        while offs < len {
+            let sym = self.read_u8(addr.wrapping_add(offs))? as char;
+            let val = digit_val(sym);
+            if val < base {
+                result = result.wrapping_mul(base).wrapping_add(val);
+                offs += 1
+            } else {
+                break;
+            }
+        }
+        if offs == 1 && sign == -1 {
+            // only character parsed was '-'
+            return Ok((0, len)); // no characters consumed, indicating error
+        } else {
+            let value = (result as SignedCell).wrapping_mul(sign) as Cell;
+            let error = len - offs;
+            return Ok((value, error));
+        }
+    }
+
+    // find the header of the word whose definition contains addr
+    fn header_containing(&self, addr: Cell) -> VMResult<Cell> {
+        let mut search_addr = self.read_cell(ADDR_LATEST)?;
+        while search_addr > addr {
+            search_addr = self.next_header(search_addr)?;
+        }
+        Ok(search_addr)
+    }
+
+    // the link to the previous header, which is always at a lower address;
+    // a link that isn't is treated as the end of the dictionary, so that a
+    // corrupted dictionary can't make a search loop forever
+    pub(crate) fn next_header(&self, header_addr: Cell) -> VMResult<Cell> {
+        let link = self.read_cell(header_addr)?;
+        Ok(if link < header_addr { link } else { 0 })
+    }
+
+    fn header_name(&self, header_addr: Cell) -> VMResult<String> {
+        let len = self.read_u8(header_addr.wrapping_add(CELL_SIZE))? & LENGTH_MASK;
+        self.read_string(header_addr.wrapping_add(CELL_SIZE + 1), len as Cell)
+    }
+
+    // name of the word currently being executed, for error reporting
+    fn current_word_name(&self) -> String {
+        match self.header_containing(self.pc.saturating_sub(CELL_SIZE)) {
+            Ok(header_addr) if header_addr != 0 => self
+                .header_name(header_addr)
+                .unwrap_or_else(|_| "?".to_owned()),
+            _ => "?".to_owned(),
+        }
+    }
+
+    fn header_addr_to_cfa(&self, addr: Cell) -> VMResult<Cell> {
+        let len = self.read_u8(addr.wrapping_add(CELL_SIZE))? & LENGTH_MASK;
+        Ok(addr.wrapping_add(len as Cell + CELL_SIZE + 1))
+    }
+
+    // everything that can fail is done before latest is updated, so that
+    // a failure doesn't leave it pointing at half a header
+    fn create(&mut self) -> VMSuccess {
+        let word_len = self.pop_data()?;
+        let word_addr = self.pop_data()?;
+        let name = match word_len {
+            0 => Vec::new(),
+            _ => self.read_bytes(word_addr, word_len)?.to_vec(),
+        };
+        self.align()?;
+        let header = self.read_cell(ADDR_HERE)?;
+        let latest = self.read_cell(ADDR_LATEST)?;
+        self.write_cell(header, latest)?;
+        let mut here = header + CELL_SIZE;
+        self.write_u8(here, word_len as u8)?;
+        here += 1;
+        for b in name {
+            self.write_u8(here, b)?;
+            here += 1;
+        }
+        self.write_cell(ADDR_HERE, here)?;
+        self.write_cell(ADDR_LATEST, header)
+    }
+
+    fn immediate(&mut self) -> VMSuccess {
+        let header_addr = self.read_cell(ADDR_LATEST)?;
+        let byte = self.read_u8(header_addr.wrapping_add(CELL_SIZE))?;
+        let byte = byte ^ IMMEDIATE_FLAG;
+        self.write_u8(header_addr.wrapping_add(CELL_SIZE), byte)
+    }
+
+    fn hidden(&mut self) -> VMSuccess {
+        let header_addr = self.pop_data()?;
+        let byte = self.read_u8(header_addr.wrapping_add(CELL_SIZE))?;
+        let byte = byte ^ HIDDEN_FLAG;
+        self.write_u8(header_addr.wrapping_add(CELL_SIZE), byte)
+    }
+
+    // the next byte of input, moving on to the next source when one runs
+    // out, or None once they all have
+    fn next_input_byte(&mut self) -> VMResult<Option<u8>> {
+        if self.line {
+            self.prompt();
+        }
+        match self.input.next() {
+            None => {
+                if self.next_source() {
+                    self.next_input_byte()
+                } else {
+                    Ok(None)
+                }
+            }
+            Some(Err(_)) => {
+                self.running = false;
+                Err(VMErrorKind::IOError)
+            }
+            Some(Ok(b)) => {
+                if b == 13 {
+                    self.line = true
+                }
+                Ok(Some(b))
+            }
+        }
+    }
+
+    fn source_index(&mut self, name: &str) -> usize {
+        match self.source_names.iter().position(|n| n == name) {
+            Some(i) => i,
+            None => {
+                self.source_names.push(name.to_owned());
+                self.source_names.len() - 1
+            }
+        }
+    }
+
+    fn start_source(&mut self, name: &str, input: Input) {
+        let file = self.source_index(name);
+        self.input = input;
+        self.location = Some(Location { file, line: 0 });
+    }
+
+    // move on to the next file to be loaded, or to the terminal once they
+    // have all been read, returning false when there's no more input
+    fn next_source(&mut self) -> bool {
+        if let Some((name, input)) = self.queued.pop_front() {
+            self.start_source(&name, input);
+            return true;
+        }
+        if !self.terminal_started && self.interactive {
+            self.terminal_started = true;
+            // show the first prompt once everything has been loaded
+            self.line = true;
+            let input = self.stdin.take().unwrap_or(Box::new(std::iter::empty()));
+            self.start_source("<stdin>", input);
+            return true;
+        }
+        if self.editor.is_some() {
+            if let Some(line) = self.read_terminal_line() {
+                self.input = Box::new(line.into_iter().map(Ok));
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Load a source file after the prelude (and any files already added),
+    /// before reading from the terminal.
+    pub fn include_file(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let f = File::open(path)?;
+        self.queued
+            .push_back((path.display().to_string(), file_input(f)));
+        Ok(())
+    }
+
+    fn exec_pc(&mut self) -> VMSuccess {
+        let xt = self.read_cell(self.pc)?;
+        self.cover(self.pc);
+        self.pc += CELL_SIZE;
+        self.exec(xt)
+    }
+
+    /// Execute the next instruction. If it fails, the VM recovers by
+    /// returning to the outer interpreter, and the error is reported at the
+    /// next prompt as well as being returned.
+    pub fn step(&mut self) -> Result<(), VMError> {
+        let kind = match self.exec_pc() {
+            Ok(()) => {
+                if self.tasks.switch_pending() {
+                    self.switch_task();
+                }
+                return Ok(());
+            }
+            Err(kind) => kind,
+        };
+        let err = VMError {
+            kind,
+            pc: self.pc,
+            data_depth: self.data_stack.len(),
+            return_depth: self.return_stack.len(),
+        };
+        match &err.kind {
+            VMErrorKind::FuelExhausted => {
+                // retrying would fail again immediately, so give up
+                let _ = writeln!(self.output, " {}", err.kind);
+                let _ = self.output.flush();
+                self.running = false;
+            }
+            _ if self.tasks.in_background() => {
+                // only the task that failed is affected
+                if self.errors.len() < 10 {
+                    self.errors.push(err.clone());
+                }
+                self.stop_failed_task();
+            }
+            kind => {
+                if self.errors.len() < 10 {
+                    if matches!(
+                        kind,
+                        VMErrorKind::DataStackOverflow(_) | VMErrorKind::ReturnStackOverflow(_)
+                    ) {
+                        // a full stack would make every following word fail too
+                        self.data_stack.clear();
+                    }
+                    self.errors.push(err.clone());
+                    // attempt recovery
+                    self.reset_input();
+                    if !self.terminal_started {
+                        // don't run the rest of a line in a file that failed
+                        self.skip_line();
+                    }
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.reset();
+                    }
+                    self.pc = self.entry
+                } else {
+                    for e in &self.errors {
+                        let _ = writeln!(self.output, " {}", e.kind);
+                    }
+                    let _ = writeln!(self.output, " too many errors, aborting");
+                    let _ = self.output.flush();
+                    self.running = false;
+                }
+            }
+        }
+        Err(err)
+    }
+
+    fn exec(&mut self, addr: Cell) -> VMSuccess {
+        self.steps += 1;
+        if let Some(max) = self.limits.max_steps {
+            if self.steps > max {
+                return Err(VMErrorKind::FuelExhausted);
+            }
+        }
+        let op: Op = self.read_u8(addr)?.into();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(addr, op);
+        }
+        match op {
+            Op::DoColonDef => {
+                self.push_return(self.pc)?;
+                self.pc = align_addr(addr.wrapping_add(1));
+            }
+            Op::Dup => {
+                let a = self.pop_data()?;
+                self.push_data(a)?;
+                self.push_data(a)?;
+            }
+            Op::Drop => {
+                self.pop_data()?;
+            }
+            Op::Swap => {
+                let a = self.pop_data()?;
+                let b = self.pop_data()?;
+                self.push_data(a)?;
+                self.push_data(b)?;
+            }
+            Op::Depth => {
+                let a = self.data_stack.len() as Cell;
+                self.push_data(a)?;
+            }
+            Op::ToR => {
+                let val = self.pop_data()?;
+                self.push_return(val)?;
+            }
+            Op::FromR => {
+                let val = self.pop_return()?;
+                self.push_data(val)?;
+            }
+            Op::Fetch => {
+                let addr = self.pop_data()?;
+                let data = self.read_cell(addr)?;
+                self.push_data(data)?;
+            }
+            Op::CFetch => {
+                let addr = self.pop_data()?;
+                let data = self.read_u8(addr)?;
+                self.push_data(data as Cell)?;
+            }
+            Op::Store => {
+                let addr = self.pop_data()?;
+                let val = self.pop_data()?;
+                self.write_cell(addr, val)?;
+            }
+            Op::CStore => {
+                let addr = self.pop_data()?;
+                let val = self.pop_data()? as u8;
+                self.write_u8(addr, val)?;
+            }
+            Op::Align => self.align()?,
+            Op::Add => {
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                self.push_data(a.wrapping_add(b))?;
+            }
+            Op::Subtract => {
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                self.push_data(a.wrapping_sub(b))?;
+            }
+            Op::Multiply => {
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                self.push_data(a.wrapping_mul(b))?;
+            }
+            Op::DivMod => self.div_mod()?,
+            Op::Equals => {
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                self.push_data(if a == b { 1 } else { 0 })?;
+            }
+            Op::LessThan => {
+                let b = self.pop_data()? as SignedCell;
+                let a = self.pop_data()? as SignedCell;
+                self.push_data(if a < b { 1 } else { 0 })?;
+            }
+            Op::GreaterThan => {
+                let b = self.pop_data()? as SignedCell;
+                let a = self.pop_data()? as SignedCell;
+                self.push_data(if a > b { 1 } else { 0 })?;
+            }
+            Op::And => {
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                self.push_data(a & b)?;
+            }
+            Op::Or => {
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                self.push_data(a | b)?;
+            }
+            Op::Xor => {
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                self.push_data(a ^ b)?;
+            }
+            Op::Invert => {
+                let a = self.pop_data()?;
+                self.push_data(!a)?;
+            }
+            Op::Lit => {
+                let value = self.read_cell(self.pc)?;
+                self.cover(self.pc);
+                self.push_data(value)?;
+                self.pc += CELL_SIZE;
+            }
+            Op::LitString => {
+                let len = self.read_cell(self.pc)?;
+                self.cover(self.pc);
+                self.pc += CELL_SIZE;
+                self.push_data(self.pc)?;
+                self.push_data(len)?;
+                self.pc = align_addr(self.pc.wrapping_add(len));
+            }
+            Op::Find => self.find()?,
+            Op::Number => self.number()?,
+            Op::ToCFA => {
+                let header_addr = self.pop_data()?;
+                self.push_data(self.header_addr_to_cfa(header_addr)?)?;
+            }
+            Op::LBracket => self.write_cell(ADDR_STATE, 0)?,
+            Op::RBracket => self.write_cell(ADDR_STATE, 1)?,
+            Op::Immediate => self.immediate()?,
+            Op::Hidden => self.hidden()?,
+            Op::Key => {
+                let data = self.key()? as Cell;
+                self.push_data(data)?
+            }
+            Op::Word => self.word()?,
+            Op::Emit => {
+                // the byte is passed through as it is, so that emitting the
+                // bytes of a UTF-8 string one at a time prints it
+                let b = self.pop_data()? as u8;
+                self.output
+                    .write_all(&[b])
+                    .map_err(|_| VMErrorKind::IOError)?;
+            }
+            Op::Create => self.create()?,
+            Op::Comma => {
+                let val = self.pop_data()?;
+                self.write_cell_here(val)?;
+            }
+            Op::CComma => {
+                let val = self.pop_data()? as u8;
+                self.write_u8_here(val)?;
+            }
+            Op::Tick => {
+                let xt = self.read_cell(self.pc)?;
+                self.cover(self.pc);
+                self.pc += CELL_SIZE;
+                self.push_data(xt)?;
+            }
+            Op::Execute => {
+                let xt = self.pop_data()?;
+                self.exec(xt)?;
+            }
+            Op::Branch => {
+                let offs = self.read_cell(self.pc)?;
+                self.cover(self.pc);
+                self.pc = self.pc.wrapping_sub(CELL_SIZE).wrapping_add(offs);
+            }
+            Op::BranchIfZero => {
+                let condition = self.pop_data()?;
+                let offs = self.read_cell(self.pc)?;
+                self.cover(self.pc);
+                if condition == 0 {
+                    self.pc = self.pc.wrapping_sub(CELL_SIZE).wrapping_add(offs);
+                } else {
+                    self.pc += CELL_SIZE;
+                }
+            }
+            Op::Exit => self.pc = self.pop_return()?,
+            Op::Reset => self.return_stack.clear(),
+            Op::Interpret => {
+                // let any other tasks run between words
+                self.pause();
+                let compiling = self.read_cell(ADDR_STATE)? != 0;
+                let (addr, len) = match self.next_word()? {
+                    Some(word) => word,
+                    None => return self.end_nested_source(),
+                };
+                let header_addr = match len <= LENGTH_MASK.into() {
+                    true => self.find_word(addr, len as u8)?,
+                    false => 0,
+                };
+                if header_addr > 0 {
+                    let flags = self.read_u8(header_addr.wrapping_add(CELL_SIZE))?;
+                    let immediate = (flags & IMMEDIATE_FLAG) != 0;
+                    let xt = self.header_addr_to_cfa(header_addr)?;
+                    if compiling && !immediate {
+                        self.compile_reference(xt, flags)?;
+                    } else {
+                        self.exec(xt)?;
+                    }
+                } else {
+                    let (value, error) = self.parse_number(addr, len)?;
+                    if error == 0 {
+                        if compiling {
+                            self.write_cell_here(self.lit)?;
+                            self.write_cell_here(value)?;
+                        } else {
+                            self.push_data(value)?
+                        }
+                    } else {
+                        return Err(VMErrorKind::UnknownWord(self.read_string(addr, len)?));
+                    }
+                }
+            }
+            Op::Allot => {
+                let n = self.pop_data()? as SignedCell;
+                self.allot(n)?;
+            }
+            Op::Unused => {
+                let n = self.unused()?;
+                self.push_data(n)?;
+            }
+            Op::Allocate => {
+                let size = self.pop_data()?;
+                match self.allocate(size)? {
+                    Some(addr) => {
+                        self.push_data(addr)?;
+                        self.push_data(0)?;
+                    }
+                    None => {
+                        self.push_data(0)?;
+                        self.push_data(IOR_ALLOCATE)?;
+                    }
+                }
+            }
+            Op::Free => {
+                let addr = self.pop_data()?;
+                let ior = if self.free(addr) { 0 } else { IOR_FREE };
+                self.push_data(ior)?;
+            }
+            Op::Resize => {
+                let size = self.pop_data()?;
+                let addr = self.pop_data()?;
+                match self.resize(addr, size)? {
+                    Some(new_addr) => {
+                        self.push_data(new_addr)?;
+                        self.push_data(0)?;
+                    }
+                    None => {
+                        self.push_data(addr)?;
+                        self.push_data(IOR_RESIZE)?;
+                    }
+                }
+            }
+            Op::OpenFile => self.open_file()?,
+            Op::CreateFile => self.create_file()?,
+            Op::CloseFile => self.close_file()?,
+            Op::ReadFile => self.read_file()?,
+            Op::ReadLine => self.read_line()?,
+            Op::WriteFile => self.write_file()?,
+            Op::WriteLine => self.write_line()?,
+            Op::FileSize => self.file_size()?,
+            Op::FilePosition => self.file_position()?,
+            Op::RepositionFile => self.reposition_file()?,
+            Op::DeleteFile => self.delete_file()?,
+            Op::RenameFile => self.rename_file()?,
+            Op::Block => {
+                let block = self.pop_data()?;
+                let addr = self.block(block)?;
+                self.push_data(addr)?;
+            }
+            Op::Buffer => {
+                let block = self.pop_data()?;
+                let addr = self.buffer(block)?;
+                self.push_data(addr)?;
+            }
+            Op::Update => self.update(),
+            Op::SaveBuffers => self.save_buffers()?,
+            Op::Flush => self.flush()?,
+            Op::Load => {
+                let block = self.pop_data()?;
+                self.load(block)?;
+            }
+            Op::TestStart => self.test_start(),
+            Op::TestResults => self.test_results(),
+            Op::TestEnd => self.test_end(),
+            Op::Inline => self.inline()?,
+            Op::NewTask => self.new_task()?,
+            Op::Activate => self.activate()?,
+            Op::Pause => self.pause(),
+            Op::Stop => self.stop(),
+            Op::Wake => self.wake()?,
+            Op::UserArea => self.user_area()?,
+            Op::XCharFetch => self.xchar_fetch()?,
+            Op::XCharStore => self.xchar_store()?,
+            Op::XCharSize => self.xchar_size()?,
+            Op::XEmit => self.xemit()?,
+            Op::XKey => self.xkey()?,
+            Op::Compare => self.compare()?,
+            Op::Search => self.search()?,
+            Op::SlashString => self.slash_string()?,
+            Op::MinusTrailing => self.minus_trailing()?,
+            Op::Blank => self.fill(Some(b' '))?,
+            Op::CMove => self.copy_bytes(CopyOrder::Ascending)?,
+            Op::CMoveUp => self.copy_bytes(CopyOrder::Descending)?,
+            Op::Move => self.copy_bytes(CopyOrder::Buffered)?,
+            Op::Fill => self.fill(None)?,
+            Op::Erase => self.fill(Some(0))?,
+            Op::Count => self.count()?,
+            Op::Type => self.type_string()?,
+            Op::Source => self.source()?,
+            Op::Refill => {
+                let flag = self.refill()?;
+                self.push_data(flag.into())?
+            }
+            Op::Parse => self.parse()?,
+            Op::Evaluate => self.evaluate()?,
+            Op::ULessThan => self.binary(|a, b| flag(a < b))?,
+            Op::UGreaterThan => self.binary(|a, b| flag(a > b))?,
+            Op::LShift => self.binary(lshift)?,
+            Op::RShift => self.binary(rshift)?,
+            Op::ARShift => self.binary(arshift)?,
+            Op::TwoStar => self.unary(|a| lshift(a, 1))?,
+            Op::TwoSlash => self.unary(|a| arshift(a, 1))?,
+            Op::Within => self.within()?,
+            Op::Min => self.binary(|a, b| (a as SignedCell).min(b as SignedCell) as Cell)?,
+            Op::Max => self.binary(|a, b| (a as SignedCell).max(b as SignedCell) as Cell)?,
+            Op::Abs => self.unary(|a| (a as SignedCell).wrapping_abs() as Cell)?,
+            Op::BitTest => self.binary(bit_test)?,
+            Op::BitSet => self.binary(bit_set)?,
+            Op::BitClear => self.binary(bit_clear)?,
+            Op::SmRem => self.divide_double(Rounding::Symmetric)?,
+            Op::FmMod => self.divide_double(Rounding::Floored)?,
+            Op::UmMod => self.um_mod()?,
+            Op::StarSlash => self.star_slash(false)?,
+            Op::StarSlashMod => self.star_slash(true)?,
+            Op::ParseName => {
+                let (addr, len) = self.parse_name()?;
+                self.push_data(addr)?;
+                self.push_data(len)?
+            }
+            Op::Optimise => self.optimise_latest()?,
+            Op::LitAdd => {
+                // lit n +
+                let n = self.read_cell(self.pc)?;
+                self.cover(self.pc);
+                self.cover(self.pc.wrapping_add(CELL_SIZE));
+                let a = self.pop_data()?;
+                self.push_data(a.wrapping_add(n))?;
+                self.pc = self.pc.wrapping_add(2 * CELL_SIZE);
+            }
+            Op::DupBranchIfZero => {
+                // dup 0branch offset
+                let condition = *self
+                    .data_stack
+                    .last()
+                    .ok_or(VMErrorKind::DataStackUnderflow)?;
+                let offs = self.read_cell(self.pc.wrapping_add(CELL_SIZE))?;
+                self.cover(self.pc);
+                self.cover(self.pc.wrapping_add(CELL_SIZE));
+                if condition == 0 {
+                    self.pc = self.pc.wrapping_add(offs);
+                } else {
+                    self.pc = self.pc.wrapping_add(2 * CELL_SIZE);
+                }
+            }
+            Op::Nip => {
+                // swap drop
+                self.cover(self.pc);
+                let b = self.pop_data()?;
+                self.pop_data()?;
+                self.push_data(b)?;
+                self.pc = self.pc.wrapping_add(CELL_SIZE);
+            }
+            Op::Over => {
+                // >r dup r> swap
+                for i in 0..3 {
+                    self.cover(self.pc.wrapping_add(CELL_SIZE * i));
+                }
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                for x in [a, b, a] {
+                    self.push_data(x)?;
+                }
+                self.pc = self.pc.wrapping_add(3 * CELL_SIZE);
+            }
+            Op::TwoDup => {
+                // over over
+                self.cover(self.pc);
+                let b = self.pop_data()?;
+                let a = self.pop_data()?;
+                for x in [a, b, a, b] {
+                    self.push_data(x)?;
+                }
+                self.pc = self.pc.wrapping_add(CELL_SIZE);
+            }
+            Op::Unknown => {
+                let opcode = self.read_u8(addr)?;
+                return Err(VMErrorKind::UnknownOpcode { opcode, addr });
+            }
+        }
+        Ok(())
+    }
+
+    fn prompt(&mut self) {
+        if self.line {
+            if self.errors.len() == 0 {
+                let _ = writeln!(self.output, " ok");
+            } else {
+                for err in self.errors.drain(..) {
+                    let _ = writeln!(self.output, " {}", err.kind);
+                }
+            }
+            // the line editor shows its own prompt
+            if self.editor.is_none() {
+                let _ = write!(self.output, ">");
+            }
+            let _ = self.output.flush();
+            self.line = false;
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn is_compiling(&self) -> bool {
+        self.read_cell(ADDR_STATE).unwrap_or(0) != 0
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// When not interactive, the VM stops once it has loaded the files it
+    /// was given, rather than going on to read from the terminal.
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// Send the output of the running program (from `emit`, the prompt and
+    /// the tester words) to `output` instead of standard output.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// The contents of the data stack, with the top of the stack last.
+    pub fn data_stack(&self) -> &[Cell] {
+        &self.data_stack
+    }
+
+    /// The VM's memory, starting at address 0 and running up to the end of
+    /// the dictionary. The heap, at the top of memory, is kept separately.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The dictionary's memory, for loading an image into it.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// The source file and line currently being read, if any.
+    pub fn current_location(&self) -> Option<(&str, u32)> {
+        self.location
+            .map(|l| (self.source_names[l.file].as_str(), l.line))
+    }
+
+    /// Take the errors waiting to be reported at the next prompt.
+    pub fn take_errors(&mut self) -> Vec<VMError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    pub fn set_blocks_path(&mut self, path: impl Into<std::path::PathBuf>) {
+        self.blocks.path = path.into();
+    }
+
+    pub fn display(&self) {
+        println!("Current word address: {:x}", self.pc);
+        println!(
+            "Data stack ({} items): {:?}",
+            self.data_stack.len(),
+            self.data_stack
+        );
+        println!(
+            "Return stack ({} items): {:?}",
+            self.return_stack.len(),
+            self.return_stack
+                .iter()
+                .map(|n| format!("{:x}", n))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    pub fn dump(&self) {
+        print!("Contents of memory:");
+        let mut line = String::with_capacity(16);
+        for (i, byte) in self.memory.iter().enumerate() {
+            if i % 16 == 0 {
+                println!("  {}", line);
+                line = String::with_capacity(16);
+                print!("({:04x}) ", i)
+            }
+            print!("{:02x} ", byte);
+            line.push(match byte {
+                0 => ' ',
+                n if *n < 32 => '?',
+                n if *n < 127 => *n as char,
+                _ => '?',
+            });
+        }
+        for _ in 0..((16 - self.memory.len() % 16) % 16) {
+            print!("   ");
+        }
+        println!("  {}", line);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{align_addr, Cell, Op, VMResult, VMSuccess, ADDR_HERE, ADDR_LATEST, CELL_SIZE, VM};
+
+// When `;` finishes a colon definition, common sequences of words in its
+// body are fused into superinstructions. The first cell of a sequence is
+// replaced by the superinstruction, which does the work of the whole
+// sequence and then skips the rest of it. The other cells are left alone,
+// so the definition stays the same size, its branch offsets stay valid, and
+// a branch to a word in the middle of a sequence still finds it there.
+//
+// Then a call to a colon word followed by exit becomes a branch to the
+// start of that word's body, so that the call doesn't use the return stack
+// and a word that ends by calling itself runs in constant space. Words that
+// could take the caller's return address off the return stack are still
+// called. If that
+// exit was the target of a branch (as in `if ... recurse then ;`), an exit
+// is added to the end of the definition for the branch to go to instead.
+#[derive(Default)]
+pub(crate) struct Peephole {
+    pub(crate) disabled: bool,
+    pub(crate) words: Words,
+}
+
+// the xts of the words that the pass knows about
+#[derive(Default)]
+pub(crate) struct Words {
+    // words followed by an inline operand, which must be stepped over
+    pub(crate) lit: Cell,
+    pub(crate) lit_string: Cell,
+    pub(crate) tick: Cell,
+    pub(crate) branch: Cell,
+    pub(crate) branch_if_zero: Cell,
+    // words that make up the sequences, or that matter when inlining
+    pub(crate) dup: Cell,
+    pub(crate) drop: Cell,
+    pub(crate) swap: Cell,
+    pub(crate) add: Cell,
+    pub(crate) to_r: Cell,
+    pub(crate) from_r: Cell,
+    pub(crate) exit: Cell,
+    pub(crate) execute: Cell,
+    pub(crate) activate: Cell,
+    pub(crate) reset: Cell,
+    // the superinstructions
+    pub(crate) lit_add: Cell,
+    pub(crate) dup_branch_if_zero: Cell,
+    pub(crate) nip: Cell,
+    pub(crate) over: Cell,
+    pub(crate) two_dup: Cell,
+}
+
+impl VM {
+    /// Turn the peephole optimiser on or off. It's on by default; turning
+    /// it off leaves compiled code exactly as the compiling words wrote it,
+    /// which can make debugging easier.
+    pub fn set_peephole(&mut self, enabled: bool) {
+        self.peephole.disabled = !enabled;
+    }
+
+    // optimise the body of the latest definition
+    pub(crate) fn optimise_latest(&mut self) -> VMSuccess {
+        if self.peephole.disabled {
+            return Ok(());
+        }
+        let latest = self.read_cell(ADDR_LATEST)?;
+        let xt = self.header_addr_to_cfa(latest)?;
+        let start = align_addr(xt.wrapping_add(1));
+        let end = self.read_cell(ADDR_HERE)?;
+        let mut addr = start;
+        while addr < end {
+            let cells = self.fuse(addr, end)?;
+            addr = addr.saturating_add(cells.saturating_mul(CELL_SIZE));
+        }
+        self.eliminate_tail_calls(start, end)
+    }
+
+    fn eliminate_tail_calls(&mut self, start: Cell, end: Cell) -> VMSuccess {
+        let w = &self.peephole.words;
+        let (branch, branch_if_zero, exit) = (w.branch, w.branch_if_zero, w.exit);
+        let mut instructions = Vec::new();
+        let mut branches = Vec::new();
+        let mut addr = start;
+        while addr < end {
+            let xt = self.read_cell(addr)?;
+            if xt == branch || xt == branch_if_zero {
+                let target = addr.wrapping_add(self.read_cell(addr.wrapping_add(CELL_SIZE))?);
+                branches.push((addr, target));
+            }
+            instructions.push(addr);
+            addr =
+                addr.saturating_add(self.instruction_cells(addr, end)?.saturating_mul(CELL_SIZE));
+        }
+        let mut lost_exits = Vec::new();
+        for pair in instructions.windows(2) {
+            let (call, next) = (pair[0], pair[1]);
+            if next != call.wrapping_add(CELL_SIZE) || self.read_cell(next)? != exit {
+                continue;
+            }
+            let xt = self.read_cell(call)?;
+            let colon = self.read_u8(xt).ok() == Some(Op::DoColonDef.into());
+            if colon && self.returns_normally(xt, &mut Vec::new())? {
+                let body = align_addr(xt.wrapping_add(1));
+                self.write_cell(call, branch)?;
+                self.write_cell(next, body.wrapping_sub(call))?;
+                lost_exits.push(next);
+            }
+        }
+        // anything that branched to an exit which is now the operand of a
+        // branch goes to a new exit at the end instead
+        let retargeted: Vec<Cell> = branches
+            .iter()
+            .filter(|(_, target)| lost_exits.contains(target))
+            .map(|&(addr, _)| addr)
+            .collect();
+        if !retargeted.is_empty() {
+            let new_exit = self.read_cell(ADDR_HERE)?;
+            self.write_cell_here(exit)?;
+            for addr in retargeted {
+                self.write_cell(addr.wrapping_add(CELL_SIZE), new_exit.wrapping_sub(addr))?;
+            }
+        }
+        Ok(())
+    }
+
+    // fuse the sequence starting at addr if there is one, returning the
+    // number of cells to move on by
+    fn fuse(&mut self, addr: Cell, end: Cell) -> VMResult<Cell> {
+        let w = &self.peephole.words;
+        let cell = |i: Cell| match addr.wrapping_add(CELL_SIZE * i) {
+            a if a < end => self.read_cell(a).map(Some),
+            _ => Ok(None),
+        };
+        let (first, second) = (cell(0)?, cell(1)?);
+        let fused = match (first, second) {
+            (Some(a), _) if a == w.lit => (cell(2)? == Some(w.add)).then_some((w.lit_add, 3)),
+            (Some(a), Some(b)) if a == w.dup && b == w.branch_if_zero => {
+                Some((w.dup_branch_if_zero, 3))
+            }
+            (Some(a), Some(b)) if a == w.swap && b == w.drop => Some((w.nip, 2)),
+            (Some(a), Some(b)) if a == b && self.is_over(a) => Some((w.two_dup, 2)),
+            // the body of over, when it has been inlined
+            (Some(_), _) if addr.wrapping_add(4 * CELL_SIZE) <= end && self.is_over_body(addr) => {
+                Some((w.over, 4))
+            }
+            _ => None,
+        };
+        if let Some((superinstruction, cells)) = fused {
+            self.write_cell(addr, superinstruction)?;
+            return Ok(cells);
+        }
+        self.instruction_cells(addr, end)
+    }
+
+    // the number of cells taken by the instruction at addr, including any
+    // inline operands; a fused over or 2dup counts as one instruction, so
+    // that the words it skips aren't taken for instructions of their own
+    pub(crate) fn instruction_cells(&self, addr: Cell, end: Cell) -> VMResult<Cell> {
+        let w = &self.peephole.words;
+        let xt = self.read_cell(addr)?;
+        Ok(
+            if [w.lit, w.tick, w.branch, w.branch_if_zero, w.lit_add].contains(&xt) {
+                2
+            } else if xt == w.over {
+                4
+            } else if xt == w.two_dup {
+                2
+            } else if xt == w.lit_string && addr.wrapping_add(CELL_SIZE) < end {
+                let len = self.read_cell(addr.wrapping_add(CELL_SIZE))?;
+                2 + align_addr(len) / CELL_SIZE
+            } else {
+                1
+            },
+        )
+    }
+
+    // whether xt is the prelude's `over`, going by its definition rather
+    // than its name so that a different word called `over` isn't fused
+    fn is_over(&self, xt: Cell) -> bool {
+        let body = align_addr(xt.wrapping_add(1));
+        self.read_u8(xt).ok() == Some(Op::DoColonDef.into())
+            && self.is_over_body(body)
+            && self.read_cell(body.wrapping_add(4 * CELL_SIZE)).ok()
+                == Some(self.peephole.words.exit)
+    }
+
+    // whether the cells at addr are `>r dup r> swap`, which may already
+    // have been fused
+    fn is_over_body(&self, addr: Cell) -> bool {
+        let w = &self.peephole.words;
+        let cell = |i: Cell| {
+            self.read_cell(addr.wrapping_add(CELL_SIZE * i))
+                .unwrap_or(0)
+        };
+        (cell(0) == w.to_r || cell(0) == w.over)
+            && [w.dup, w.from_r, w.swap]
+                .iter()
+                .zip(1..)
+                .all(|(&expected, i)| cell(i) == expected)
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::{Cell, Op, VM};
+
+#[derive(Default, Clone, Copy)]
+struct WordStats {
+    calls: u64,
+    inclusive: u64,
+    exclusive: u64,
+}
+
+struct Frame {
+    xt: Cell,
+    start: u64,
+}
+
+// Every instruction executed counts once. A primitive's instructions are
+// attributed to itself and also (as exclusive time) to the colon word whose
+// body executed it, so a colon word's exclusive count is the cost of its
+// own body and its inclusive count adds everything it called.
+#[derive(Default)]
+pub(crate) struct Profiler {
+    total: u64,
+    frames: Vec<Frame>,
+    words: HashMap<Cell, WordStats>,
+    folded: HashMap<Vec<Cell>, u64>,
+}
+
+impl Profiler {
+    pub(crate) fn record(&mut self, xt: Cell, op: Op) {
+        self.total += 1;
+        self.words.entry(xt).or_default().calls += 1;
+        let mut stack: Vec<Cell> = self.frames.iter().map(|f| f.xt).collect();
+        match op {
+            Op::DoColonDef => {
+                self.frames.push(Frame {
+                    xt,
+                    start: self.total - 1,
+                });
+                self.words.entry(xt).or_default().exclusive += 1;
+                stack.push(xt);
+            }
+            _ => {
+                let stats = self.words.entry(xt).or_default();
+                stats.inclusive += 1;
+                stats.exclusive += 1;
+                if let Some(frame) = self.frames.last() {
+                    self.words.entry(frame.xt).or_default().exclusive += 1;
+                }
+                stack.push(xt);
+            }
+        }
+        *self.folded.entry(stack).or_default() += 1;
+        match op {
+            Op::Exit => self.pop_frame(),
+            Op::Reset => self.reset(),
+            _ => (),
+        }
+    }
+
+    fn pop_frame(&mut self) {
+        if let Some(frame) = self.frames.pop() {
+            // only the outermost activation of a recursive word counts
+            // towards its inclusive total, otherwise it would be counted
+            // several times over
+            if !self.frames.iter().any(|f| f.xt == frame.xt) {
+                self.words.entry(frame.xt).or_default().inclusive += self.total - frame.start;
+            }
+        }
+    }
+
+    // called when the return stack is discarded
+    pub(crate) fn reset(&mut self) {
+        while !self.frames.is_empty() {
+            self.pop_frame();
+        }
+    }
+}
+
+impl VM {
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::default());
+    }
+
+    fn xt_name(&self, xt: Cell) -> String {
+        match self.header_containing(xt) {
+            Ok(header_addr) if header_addr != 0 => self
+                .header_name(header_addr)
+                .unwrap_or_else(|_| format!("{:#x}", xt)),
+            _ => format!("{:#x}", xt),
+        }
+    }
+
+    /// A table of calls and instruction counts for each word executed
+    /// since profiling was enabled, most expensive first.
+    pub fn profile_report(&self) -> Option<String> {
+        let profiler = self.profiler.as_ref()?;
+        let mut words: Vec<_> = profiler.words.iter().collect();
+        words.sort_by_key(|(&xt, stats)| (std::cmp::Reverse(stats.inclusive), xt));
+        let mut report = format!(
+            "{:<24} {:>12} {:>14} {:>14}\n",
+            "word", "calls", "inclusive", "exclusive"
+        );
+        for (&xt, stats) in words {
+            let _ = writeln!(
+                report,
+                "{:<24} {:>12} {:>14} {:>14}",
+                self.xt_name(xt),
+                stats.calls,
+                stats.inclusive,
+                stats.exclusive
+            );
+        }
+        let _ = writeln!(report, "{} instructions executed", profiler.total);
+        Some(report)
+    }
+
+    /// Write the profile in the folded stack format used by flame graph
+    /// tools: one line per call stack, with its instruction count.
+    pub fn write_folded_stacks(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let profiler = match &self.profiler {
+            Some(profiler) => profiler,
+            None => return Ok(()),
+        };
+        let mut lines: Vec<(String, u64)> = profiler
+            .folded
+            .iter()
+            .map(|(stack, &count)| {
+                let names: Vec<String> = stack.iter().map(|&xt| self.xt_name(xt)).collect();
+                (names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        for (stack, count) in lines {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
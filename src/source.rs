@@ -0,0 +1,301 @@
+use crate::{
+    Cell, Location, VMErrorKind, VMResult, VMSuccess, ADDR_HERE, ADDR_WORD_BUFFER, LENGTH_MASK, VM,
+};
+
+// bytes in the terminal input buffer
+pub(crate) const TIB_SIZE: Cell = 1024;
+
+// The text being interpreted is a buffer in memory, which `source` gives
+// the address and length of, and `>in` is the offset of the next byte to
+// be parsed from it. Input from files and the terminal is read into the
+// terminal input buffer a line at a time; a line too long for it is read
+// in pieces. A block being loaded, or a string being evaluated, is
+// interpreted where it is, as a source nested inside the one that loaded
+// or evaluated it.
+#[derive(Clone, Copy)]
+pub(crate) enum SourceKind {
+    Input,
+    Block(Cell),
+    Text,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Source {
+    kind: SourceKind,
+    addr: Cell,
+    len: Cell,
+    // the newline or carriage return that ended the line in the buffer,
+    // which key reads after the last byte of the line unless parsing has
+    // already gone past it
+    line_end: Option<u8>,
+    // saved while a nested source is being interpreted
+    to_in: Cell,
+    location: Option<Location>,
+    // a copy of the text on the heap, freed when the source ends
+    copy: Option<Cell>,
+}
+
+impl Source {
+    pub(crate) fn input() -> Self {
+        Self {
+            kind: SourceKind::Input,
+            addr: 0,
+            len: 0,
+            line_end: None,
+            to_in: 0,
+            location: None,
+            copy: None,
+        }
+    }
+
+    fn nested(kind: SourceKind, addr: Cell, len: Cell) -> Self {
+        Self {
+            kind,
+            addr,
+            len,
+            ..Self::input()
+        }
+    }
+}
+
+// blank delimits anything that looks like space, so that tabs and
+// newlines (from a block or a string) separate words too
+fn is_delimiter(b: u8, delimiter: u8) -> bool {
+    match delimiter {
+        b' ' => b.is_ascii_whitespace(),
+        _ => b == delimiter,
+    }
+}
+
+impl VM {
+    fn to_in(&self) -> VMResult<Cell> {
+        self.read_cell(self.addr_to_in)
+    }
+
+    fn set_to_in(&mut self, to_in: Cell) -> VMSuccess {
+        self.write_cell(self.addr_to_in, to_in)
+    }
+
+    // read the next line of input into the terminal input buffer,
+    // returning false if there's no more input
+    fn read_input_line(&mut self) -> VMResult<bool> {
+        let mut line = Vec::new();
+        let mut line_end = None;
+        while line.len() < TIB_SIZE as usize {
+            match self.next_input_byte()? {
+                None if line.is_empty() => return Ok(false),
+                None => break,
+                Some(b) if b == b'\n' || b == 13 => {
+                    line_end = Some(b);
+                    break;
+                }
+                Some(b) => line.push(b),
+            }
+        }
+        self.bytes_mut(self.tib, line.len() as Cell)?
+            .copy_from_slice(&line);
+        self.source.addr = self.tib;
+        self.source.len = line.len() as Cell;
+        self.source.line_end = line_end;
+        if let Some(location) = &mut self.location {
+            location.line += 1;
+        }
+        self.set_to_in(0)?;
+        Ok(true)
+    }
+
+    // move on to the next line of the current source, returning false at
+    // the end of it; a block is a single line
+    pub(crate) fn refill(&mut self) -> VMResult<bool> {
+        match self.source.kind {
+            SourceKind::Input => self.read_input_line(),
+            SourceKind::Block(_) | SourceKind::Text => Ok(false),
+        }
+    }
+
+    // what happens when there's nothing more to read: the end of a nested
+    // source is an error for the word trying to read it, but the end of
+    // the input stops the VM
+    fn end_of_source(&mut self) -> VMErrorKind {
+        if matches!(self.source.kind, SourceKind::Input) {
+            self.running = false;
+        }
+        VMErrorKind::Terminated
+    }
+
+    // read the next byte of the source, including the end of each line
+    pub(crate) fn key(&mut self) -> VMResult<u8> {
+        loop {
+            let to_in = self.to_in()?;
+            if to_in < self.source.len {
+                self.set_to_in(to_in + 1)?;
+                return self.read_u8(self.source.addr.wrapping_add(to_in));
+            }
+            if let Some(b) = self.source.line_end.take() {
+                return Ok(b);
+            }
+            if !self.refill()? {
+                return Err(self.end_of_source());
+            }
+        }
+    }
+
+    // parse text up to the delimiter, optionally skipping delimiters
+    // before it, and move >in past the delimiter that ends it; the end of
+    // the line counts as a delimiter, so text that runs up to it leaves
+    // nothing more for key to read from the line
+    fn parse_until(&mut self, delimiter: u8, skip: bool) -> VMResult<(Cell, Cell)> {
+        let (addr, len) = (self.source.addr, self.source.len);
+        let mut start = self.to_in()?.min(len);
+        let text = match len {
+            0 => &[],
+            _ => self.read_bytes(addr, len)?,
+        };
+        if skip {
+            while start < len && is_delimiter(text[start as usize], delimiter) {
+                start += 1;
+            }
+        }
+        let end = text[start as usize..]
+            .iter()
+            .position(|&b| is_delimiter(b, delimiter))
+            .map_or(len, |i| start + i as Cell);
+        if end == len {
+            self.source.line_end = None;
+        }
+        self.set_to_in((end + 1).min(len))?;
+        Ok((addr.wrapping_add(start), end - start))
+    }
+
+    pub(crate) fn parse_name(&mut self) -> VMResult<(Cell, Cell)> {
+        self.parse_until(b' ', true)
+    }
+
+    // ( char -- c-addr u )
+    pub(crate) fn parse(&mut self) -> VMSuccess {
+        let delimiter = self.pop_data()? as u8;
+        let (addr, len) = self.parse_until(delimiter, false)?;
+        self.push_data(addr)?;
+        self.push_data(len)
+    }
+
+    // ( char -- c-addr u ) parse a word into the word buffer, which keeps
+    // it after the line it came from has gone
+    pub(crate) fn word(&mut self) -> VMSuccess {
+        let delimiter = self.pop_data()? as u8;
+        let (addr, len) = self.parse_until(delimiter, true)?;
+        let len = len.min(LENGTH_MASK.into());
+        let bytes = self.read_bytes(addr, len)?.to_vec();
+        self.bytes_mut(ADDR_WORD_BUFFER, len)?
+            .copy_from_slice(&bytes);
+        self.push_data(ADDR_WORD_BUFFER)?;
+        self.push_data(len)
+    }
+
+    // ( -- c-addr u )
+    pub(crate) fn source(&mut self) -> VMSuccess {
+        self.push_data(self.source.addr)?;
+        self.push_data(self.source.len)
+    }
+
+    // the next word to interpret, reading more lines as needed, or None at
+    // the end of a nested source
+    pub(crate) fn next_word(&mut self) -> VMResult<Option<(Cell, Cell)>> {
+        loop {
+            let (addr, len) = self.parse_name()?;
+            if len > 0 {
+                return Ok(Some((addr, len)));
+            }
+            if !self.refill()? {
+                return match self.sources.is_empty() {
+                    true => Err(self.end_of_source()),
+                    false => Ok(None),
+                };
+            }
+        }
+    }
+
+    // discard the rest of the current line
+    pub(crate) fn skip_line(&mut self) {
+        let _ = self.set_to_in(self.source.len);
+    }
+
+    // interpret the source until it is exhausted, then carry on from the
+    // current pc; this runs in the main loop rather than recursively, so
+    // that the source can contain colon definitions and immediate words,
+    // and start or end a definition that continues outside it
+    fn interpret_nested(&mut self, source: Source) -> VMSuccess {
+        self.push_return(self.pc)?;
+        let mut outer = self.source;
+        outer.to_in = self.to_in()?;
+        outer.location = self.location;
+        self.sources.push(outer);
+        self.source = source;
+        self.set_to_in(0)?;
+        self.pc = self.nested_interpreter;
+        Ok(())
+    }
+
+    pub(crate) fn interpret_block(&mut self, block: Cell, addr: Cell, len: Cell) -> VMSuccess {
+        self.interpret_nested(Source::nested(SourceKind::Block(block), addr, len))?;
+        let file = self.source_index(&format!("block {}", block));
+        self.location = Some(Location { file, line: 1 });
+        Ok(())
+    }
+
+    // ( c-addr u -- ) text in the space above here, such as a string left
+    // there by s" when interpreting, would be overwritten by anything it
+    // compiled, so it is interpreted from a copy
+    pub(crate) fn evaluate(&mut self) -> VMSuccess {
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        let mut source = Source::nested(SourceKind::Text, addr, len);
+        if len > 0 {
+            let text = self.read_bytes(addr, len)?.to_vec();
+            let here = self.read_cell(ADDR_HERE)?;
+            if addr.saturating_add(len) > here && addr < self.heap_floor() {
+                let copy = self
+                    .allocate(len)?
+                    .ok_or(VMErrorKind::MemoryLimitExceeded)?;
+                self.bytes_mut(copy, len)?.copy_from_slice(&text);
+                source.addr = copy;
+                source.copy = Some(copy);
+            }
+        }
+        self.interpret_nested(source)
+    }
+
+    fn restore_source(&mut self, source: Source) -> VMSuccess {
+        if let Some(copy) = self.source.copy {
+            self.free(copy);
+        }
+        self.source = source;
+        self.location = source.location;
+        if let SourceKind::Block(block) = source.kind {
+            // the block may have been moved out of its buffer meanwhile
+            self.source.addr = self.block(block)?;
+        }
+        self.set_to_in(source.to_in)
+    }
+
+    pub(crate) fn end_nested_source(&mut self) -> VMSuccess {
+        if let Some(outer) = self.sources.pop() {
+            self.restore_source(outer)?;
+        }
+        self.pc = self.pop_return()?;
+        Ok(())
+    }
+
+    // go back to reading the input after an error in a nested source
+    pub(crate) fn reset_input(&mut self) {
+        if !self.sources.is_empty() {
+            let copies: Vec<Cell> = self.sources.iter().filter_map(|s| s.copy).collect();
+            for copy in copies {
+                self.free(copy);
+            }
+            let outer = self.sources.swap_remove(0);
+            self.sources.clear();
+            let _ = self.restore_source(outer);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::cmp::Ordering;
+use std::io::Write;
+
+use crate::{Cell, VMErrorKind, VMResult, VMSuccess, VM};
+
+// the order in which cmove, cmove> and move copy bytes
+pub(crate) enum CopyOrder {
+    Ascending,
+    Descending,
+    Buffered,
+}
+
+// The string words work directly on ranges of memory. A range of length
+// zero is never touched, so its address doesn't need to be valid; any
+// other range must lie within memory (or, for a destination, be somewhere
+// that c! could write to).
+
+impl VM {
+    fn check_source(&self, addr: Cell, len: Cell) -> VMSuccess {
+        self.read_bytes(addr, len).map(|_| ())
+    }
+
+    pub(crate) fn destination(&mut self, addr: Cell, len: Cell) -> VMResult<&mut [u8]> {
+        self.extend_memory(addr, len as usize)?;
+        self.bytes_mut(addr, len)
+    }
+
+    // ( c-addr u -- ) pops a string, which is empty or within memory
+    fn pop_string(&mut self) -> VMResult<(Cell, Cell)> {
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        if len > 0 {
+            self.check_source(addr, len)?;
+        }
+        Ok((addr, len))
+    }
+
+    fn string(&self, addr: Cell, len: Cell) -> &[u8] {
+        match len {
+            0 => &[],
+            _ => self.read_bytes(addr, len).unwrap_or_default(),
+        }
+    }
+
+    // compare ( c-addr1 u1 c-addr2 u2 -- n )
+    pub(crate) fn compare(&mut self) -> VMSuccess {
+        let (addr2, len2) = self.pop_string()?;
+        let (addr1, len1) = self.pop_string()?;
+        let n = match self.string(addr1, len1).cmp(self.string(addr2, len2)) {
+            Ordering::Less => -1i32,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        };
+        self.push_data(n as Cell)
+    }
+
+    // search ( c-addr1 u1 c-addr2 u2 -- c-addr3 u3 flag )
+    pub(crate) fn search(&mut self) -> VMSuccess {
+        let (addr2, len2) = self.pop_string()?;
+        let (addr1, len1) = self.pop_string()?;
+        let haystack = self.string(addr1, len1);
+        let needle = self.string(addr2, len2);
+        let found = match needle.len() {
+            0 => Some(0),
+            n => haystack.windows(n).position(|w| w == needle),
+        };
+        match found {
+            Some(i) => {
+                self.push_data(addr1 + i as Cell)?;
+                self.push_data(len1 - i as Cell)?;
+                self.push_data(1)
+            }
+            None => {
+                self.push_data(addr1)?;
+                self.push_data(len1)?;
+                self.push_data(0)
+            }
+        }
+    }
+
+    // /string ( c-addr1 u1 n -- c-addr2 u2 )
+    pub(crate) fn slash_string(&mut self) -> VMSuccess {
+        let n = self.pop_data()?;
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        self.push_data(addr.wrapping_add(n))?;
+        self.push_data(len.wrapping_sub(n))
+    }
+
+    // -trailing ( c-addr u1 -- c-addr u2 )
+    pub(crate) fn minus_trailing(&mut self) -> VMSuccess {
+        let (addr, len) = self.pop_string()?;
+        let spaces = self
+            .string(addr, len)
+            .iter()
+            .rev()
+            .take_while(|&&b| b == b' ')
+            .count();
+        self.push_data(addr)?;
+        self.push_data(len - spaces as Cell)
+    }
+
+    // fill ( c-addr u char -- ), and blank and erase, which fill with
+    // spaces and zeros
+    pub(crate) fn fill(&mut self, byte: Option<u8>) -> VMSuccess {
+        let byte = match byte {
+            Some(b) => b,
+            None => self.pop_data()? as u8,
+        };
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        if len > 0 {
+            self.destination(addr, len)?.fill(byte);
+        }
+        Ok(())
+    }
+
+    // cmove ( c-addr1 c-addr2 u -- ), cmove> and move; cmove copies from
+    // the lowest address up and cmove> from the highest down, which
+    // matters when the ranges overlap, whereas move copies as if through a
+    // buffer
+    pub(crate) fn copy_bytes(&mut self, order: CopyOrder) -> VMSuccess {
+        let len = self.pop_data()?;
+        let to = self.pop_data()?;
+        let from = self.pop_data()?;
+        if len == 0 {
+            return Ok(());
+        }
+        self.check_source(from, len)?;
+        self.destination(to, len)?;
+        let overlapping = to < from + len && from < to + len;
+        let copy_one_at_a_time = match order {
+            CopyOrder::Ascending => overlapping && to > from,
+            CopyOrder::Descending => overlapping && to < from,
+            CopyOrder::Buffered => false,
+        };
+        if copy_one_at_a_time {
+            // overlapping ranges are in the same part of memory, so copy
+            // within the bytes that cover both
+            let start = from.min(to);
+            let bytes = self.bytes_mut(start, from.max(to) - start + len)?;
+            let (from, to) = ((from - start) as usize, (to - start) as usize);
+            let pairs = (from..from + len as usize).zip(to..to + len as usize);
+            if matches!(order, CopyOrder::Ascending) {
+                pairs.for_each(|(i, j)| bytes[j] = bytes[i]);
+            } else {
+                pairs.rev().for_each(|(i, j)| bytes[j] = bytes[i]);
+            }
+        } else {
+            let data = self.read_bytes(from, len)?.to_vec();
+            self.bytes_mut(to, len)?.copy_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    // count ( c-addr1 -- c-addr2 u )
+    pub(crate) fn count(&mut self) -> VMSuccess {
+        let addr = self.pop_data()?;
+        let len = self.read_u8(addr)?;
+        self.push_data(addr.wrapping_add(1))?;
+        self.push_data(len.into())
+    }
+
+    // type ( c-addr u -- )
+    pub(crate) fn type_string(&mut self) -> VMSuccess {
+        let (addr, len) = self.pop_string()?;
+        let bytes = self.string(addr, len).to_vec();
+        self.output
+            .write_all(&bytes)
+            .map_err(|_| VMErrorKind::IOError)
+    }
+}
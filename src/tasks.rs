@@ -0,0 +1,166 @@
+use std::mem;
+
+use crate::{Cell, VMErrorKind, VMResult, VMSuccess, VM};
+
+// bytes in each task's user area
+pub(crate) const USER_SIZE: Cell = 128;
+
+// A task has its own stacks and pc, and a user area in memory for variables
+// of its own; it is known by the address of its user area. The running
+// task's stacks and pc are the VM's own, and are swapped with the saved
+// ones when another task runs. Task 0 is the operator task, which runs the
+// outer interpreter and never sleeps.
+struct Task {
+    data_stack: Vec<Cell>,
+    return_stack: Vec<Cell>,
+    pc: Cell,
+    user: Cell,
+    awake: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct Tasks {
+    tasks: Vec<Task>,
+    current: usize,
+    // code for a task to return to when it gets to the end of the code
+    // that activated it, which stops it
+    pub(crate) end: Cell,
+    // set by pause and stop, and acted on by step
+    switch: bool,
+}
+
+impl Tasks {
+    pub(crate) fn new(operator_user: Cell, end: Cell) -> Self {
+        Self {
+            tasks: vec![Task {
+                data_stack: Vec::new(),
+                return_stack: Vec::new(),
+                pc: 0,
+                user: operator_user,
+                awake: true,
+            }],
+            current: 0,
+            end,
+            switch: false,
+        }
+    }
+
+    fn index(&self, user: Cell) -> VMResult<usize> {
+        self.tasks
+            .iter()
+            .position(|t| t.user == user)
+            .ok_or(VMErrorKind::InvalidTask(user))
+    }
+
+    pub(crate) fn in_background(&self) -> bool {
+        self.current != 0
+    }
+
+    pub(crate) fn switch_pending(&self) -> bool {
+        self.switch
+    }
+}
+
+impl VM {
+    // ( user -- ) make a task with its user area at user
+    pub(crate) fn new_task(&mut self) -> VMSuccess {
+        let user = self.pop_data()?;
+        if self.tasks.index(user).is_ok() {
+            return Err(VMErrorKind::InvalidTask(user));
+        }
+        self.tasks.tasks.push(Task {
+            data_stack: Vec::new(),
+            return_stack: Vec::new(),
+            pc: 0,
+            user,
+            awake: false,
+        });
+        Ok(())
+    }
+
+    // ( task -- ) start the task running the rest of the current
+    // definition, and return from the definition
+    pub(crate) fn activate(&mut self) -> VMSuccess {
+        let user = self.pop_data()?;
+        let i = self.tasks.index(user)?;
+        if i == 0 {
+            return Err(VMErrorKind::InvalidTask(user));
+        }
+        let resume = self.pop_return()?;
+        let start = self.pc;
+        let end = self.tasks.end;
+        if i == self.tasks.current {
+            self.data_stack.clear();
+            self.return_stack = vec![end];
+        } else {
+            let task = &mut self.tasks.tasks[i];
+            task.data_stack.clear();
+            task.return_stack = vec![end];
+            task.pc = start;
+            task.awake = true;
+            self.pc = resume;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn pause(&mut self) {
+        self.tasks.switch = true;
+    }
+
+    // put the current task to sleep until something wakes it
+    pub(crate) fn stop(&mut self) {
+        if self.tasks.in_background() {
+            self.tasks.tasks[self.tasks.current].awake = false;
+        }
+        self.tasks.switch = true;
+    }
+
+    // ( task -- ) a task that activate hasn't started has nothing to run,
+    // so it can't be woken
+    pub(crate) fn wake(&mut self) -> VMSuccess {
+        let user = self.pop_data()?;
+        let i = self.tasks.index(user)?;
+        if i != 0 && self.tasks.tasks[i].pc == 0 {
+            return Err(VMErrorKind::InvalidTask(user));
+        }
+        self.tasks.tasks[i].awake = true;
+        Ok(())
+    }
+
+    // ( -- addr ) the current task's user area
+    pub(crate) fn user_area(&mut self) -> VMSuccess {
+        self.push_data(self.tasks.tasks[self.tasks.current].user)
+    }
+
+    // run the next task that is awake, round-robin; the operator task is
+    // always awake, so there is one
+    pub(crate) fn switch_task(&mut self) {
+        self.tasks.switch = false;
+        let count = self.tasks.tasks.len();
+        let current = self.tasks.current;
+        let next = (1..=count)
+            .map(|n| (current + n) % count)
+            .find(|&i| self.tasks.tasks[i].awake)
+            .unwrap_or(0);
+        if next == current {
+            return;
+        }
+        let task = &mut self.tasks.tasks[current];
+        mem::swap(&mut task.data_stack, &mut self.data_stack);
+        mem::swap(&mut task.return_stack, &mut self.return_stack);
+        task.pc = self.pc;
+        let task = &mut self.tasks.tasks[next];
+        mem::swap(&mut task.data_stack, &mut self.data_stack);
+        mem::swap(&mut task.return_stack, &mut self.return_stack);
+        self.pc = task.pc;
+        self.tasks.current = next;
+    }
+
+    // a task that fails is stopped, leaving the other tasks running
+    pub(crate) fn stop_failed_task(&mut self) {
+        self.data_stack.clear();
+        self.return_stack.clear();
+        self.stop();
+        self.switch_task();
+    }
+}
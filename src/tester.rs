@@ -0,0 +1,69 @@
+use std::io::Write;
+
+use crate::{Cell, SignedCell, VM};
+
+// State for the Hayes-style tester words: T{ notes the stack depth,
+// -> saves whatever the code under test left above it, and }T compares
+// that with the expected results.
+#[derive(Default)]
+pub(crate) struct Tester {
+    start_depth: usize,
+    actual: Vec<Cell>,
+    pub(crate) passed: u32,
+    pub(crate) failed: u32,
+}
+
+fn format_stack(stack: &[Cell]) -> String {
+    let items: Vec<String> = stack
+        .iter()
+        .map(|&n| (n as SignedCell).to_string())
+        .collect();
+    format!("<{}> {}", stack.len(), items.join(" "))
+}
+
+impl VM {
+    pub(crate) fn test_start(&mut self) {
+        self.tester.start_depth = self.data_stack.len();
+    }
+
+    pub(crate) fn test_results(&mut self) {
+        let start = self.tester.start_depth.min(self.data_stack.len());
+        self.tester.actual = self.data_stack.split_off(start);
+    }
+
+    pub(crate) fn test_end(&mut self) {
+        let start = self.tester.start_depth.min(self.data_stack.len());
+        let expected = self.data_stack.split_off(start);
+        if expected == self.tester.actual {
+            self.tester.passed += 1;
+        } else {
+            self.tester.failed += 1;
+            let problem = if expected.len() == self.tester.actual.len() {
+                "incorrect result"
+            } else {
+                "wrong number of results"
+            };
+            let message = format!(
+                "{}{}: expected {}, got {}",
+                self.location_prefix(),
+                problem,
+                format_stack(&expected),
+                format_stack(&self.tester.actual)
+            );
+            let _ = writeln!(self.output, "{}", message);
+        }
+    }
+
+    // "file:line: " for the source being read, if any
+    pub(crate) fn location_prefix(&self) -> String {
+        match self.current_location() {
+            Some((file, line)) => format!("{}:{}: ", file, line),
+            None => String::new(),
+        }
+    }
+
+    /// The number of tests that have passed and failed so far.
+    pub fn test_counts(&self) -> (u32, u32) {
+        (self.tester.passed, self.tester.failed)
+    }
+}
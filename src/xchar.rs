@@ -0,0 +1,96 @@
+use std::io::Write;
+
+use crate::{Cell, VMErrorKind, VMResult, VMSuccess, VM};
+
+// Extended characters are Unicode code points, stored in memory and read
+// and written as UTF-8. A malformed sequence is taken to be a character
+// of its own for each byte, so that bytes which aren't UTF-8 still get
+// through.
+
+// the size of the UTF-8 sequence that starts with lead
+fn sequence_size(lead: u8) -> usize {
+    match lead {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    }
+}
+
+// the xchar at the start of bytes, and the number of bytes it takes
+fn decode(bytes: &[u8]) -> (Cell, Cell) {
+    let Some(&lead) = bytes.first() else {
+        return (0, 0);
+    };
+    let size = sequence_size(lead);
+    match bytes.get(..size).map(std::str::from_utf8) {
+        Some(Ok(s)) => (s.chars().next().map_or(0, Cell::from), size as Cell),
+        _ => (lead.into(), 1),
+    }
+}
+
+// code points that can't be encoded become U+FFFD
+fn encode(xchar: Cell) -> Vec<u8> {
+    let c = u32::try_from(xchar)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or(char::REPLACEMENT_CHARACTER);
+    c.to_string().into_bytes()
+}
+
+impl VM {
+    // the bytes starting at addr that could hold an xchar
+    fn xchar_bytes(&self, addr: Cell, max: Cell) -> VMResult<&[u8]> {
+        let (bytes, base) = self.region(addr);
+        let available = (base + bytes.len() as Cell).saturating_sub(addr);
+        self.read_bytes(addr, max.min(4).min(available).max(1))
+    }
+
+    // xc@+ ( xc-addr1 -- xc-addr2 xchar )
+    pub(crate) fn xchar_fetch(&mut self) -> VMSuccess {
+        let addr = self.pop_data()?;
+        let (xchar, size) = decode(self.xchar_bytes(addr, 4)?);
+        self.push_data(addr.wrapping_add(size))?;
+        self.push_data(xchar)
+    }
+
+    // xc!+ ( xchar xc-addr1 -- xc-addr2 )
+    pub(crate) fn xchar_store(&mut self) -> VMSuccess {
+        let addr = self.pop_data()?;
+        let xchar = self.pop_data()?;
+        let bytes = encode(xchar);
+        for (i, &b) in bytes.iter().enumerate() {
+            self.write_u8(addr.wrapping_add(i as Cell), b)?;
+        }
+        self.push_data(addr.wrapping_add(bytes.len() as Cell))
+    }
+
+    // x-size ( xc-addr u1 -- u2 )
+    pub(crate) fn xchar_size(&mut self) -> VMSuccess {
+        let len = self.pop_data()?;
+        let addr = self.pop_data()?;
+        let size = match len {
+            0 => 0,
+            _ => decode(self.xchar_bytes(addr, len)?).1,
+        };
+        self.push_data(size)
+    }
+
+    // xemit ( xchar -- )
+    pub(crate) fn xemit(&mut self) -> VMSuccess {
+        let xchar = self.pop_data()?;
+        self.output
+            .write_all(&encode(xchar))
+            .map_err(|_| VMErrorKind::IOError)
+    }
+
+    // xkey ( -- xchar )
+    pub(crate) fn xkey(&mut self) -> VMSuccess {
+        let lead = self.key()?;
+        let mut bytes = vec![lead];
+        for _ in 1..sequence_size(lead) {
+            bytes.push(self.key()?);
+        }
+        self.push_data(decode(&bytes).0)
+    }
+}
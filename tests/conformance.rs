@@ -0,0 +1,107 @@
+//! Runs the core word set tests from the Forth 2012 test suite and checks
+//! which of its sections pass, so that a change which breaks a section
+//! (or fixes one) shows up here.
+
+use froth::{Limits, VMErrorKind, VM};
+
+const SUPPORT: &str = "tests/forth2012/support.fr";
+const CORE_TESTS: &str = "tests/forth2012/core.fr";
+
+// Sections of core.fr in which every test passes. Add a section here when
+// a change makes it pass.
+const PASSING: &[&str] = &["BASIC ASSUMPTIONS", "BOOLEANS: INVERT AND OR XOR"];
+
+// generous enough for the whole suite, but stops a runaway loop
+const MAX_STEPS: u64 = 50_000_000;
+
+struct Section {
+    name: String,
+    line: u32,
+    problems: Vec<String>,
+}
+
+// the sections of the test file that contain tests, in order
+fn sections(source: &str) -> Vec<Section> {
+    let mut sections: Vec<Section> = Vec::new();
+    let mut has_tests = false;
+    for (i, text) in source.lines().enumerate() {
+        if let Some(name) = text.strip_prefix("TESTING ") {
+            if !has_tests {
+                sections.pop();
+            }
+            sections.push(Section {
+                name: name.trim().to_owned(),
+                line: i as u32 + 1,
+                problems: Vec::new(),
+            });
+            has_tests = false;
+        } else if text.contains("T{") {
+            has_tests = true;
+        }
+    }
+    if !has_tests {
+        sections.pop();
+    }
+    sections
+}
+
+fn record(sections: &mut [Section], vm: &VM, problem: String) {
+    let line = match vm.current_location() {
+        Some((file, line)) if file == CORE_TESTS => line,
+        _ => return,
+    };
+    if let Some(section) = sections.iter_mut().rev().find(|s| s.line <= line) {
+        section.problems.push(format!("{}: {}", line, problem));
+    }
+}
+
+#[test]
+fn core_word_set() {
+    let source = std::fs::read_to_string(CORE_TESTS).unwrap();
+    let mut sections = sections(&source);
+
+    let mut vm = VM::with_input(std::io::empty());
+    vm.set_interactive(false);
+    vm.set_limits(Limits {
+        max_steps: Some(MAX_STEPS),
+        ..Limits::default()
+    });
+    vm.init();
+    vm.include_file(SUPPORT).unwrap();
+    vm.include_file(CORE_TESTS).unwrap();
+
+    let mut failed = 0;
+    while vm.is_running() {
+        let result = vm.step();
+        if vm.test_counts().1 > failed {
+            failed = vm.test_counts().1;
+            record(&mut sections, &vm, "test failed".to_owned());
+        }
+        if let Err(e) = result {
+            match e.kind {
+                VMErrorKind::Terminated => (),
+                VMErrorKind::FuelExhausted => panic!("test suite did not finish"),
+                kind => record(&mut sections, &vm, kind.to_string()),
+            }
+            vm.take_errors();
+        }
+    }
+
+    let mut passing = Vec::new();
+    for section in &sections {
+        if section.problems.is_empty() {
+            println!("PASS {}", section.name);
+            passing.push(section.name.as_str());
+        } else {
+            println!("FAIL {}", section.name);
+            for problem in &section.problems {
+                println!("    {}", problem);
+            }
+        }
+    }
+    assert_eq!(
+        passing, PASSING,
+        "passing sections of {} changed",
+        CORE_TESTS
+    );
+}
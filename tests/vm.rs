@@ -0,0 +1,1023 @@
+//! Runs snippets of Forth through the VM (after the prelude) and checks
+//! the stack, memory, output and errors they leave behind.
+
+use std::cell::RefCell;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use froth::{Cell, Limits, SignedCell, VMErrorKind, CELL_SIZE, VM};
+
+// collects the VM's output where the test can see it
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Run {
+    vm: VM,
+    output: String,
+    errors: Vec<VMErrorKind>,
+}
+
+impl Run {
+    fn stack(&self) -> Vec<SignedCell> {
+        self.vm
+            .data_stack()
+            .iter()
+            .map(|&n| n as SignedCell)
+            .collect()
+    }
+
+    fn cell(&self, addr: Cell) -> Cell {
+        let addr = addr as usize;
+        let bytes = &self.vm.memory()[addr..addr + CELL_SIZE as usize];
+        Cell::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+fn vm_for(source: &str) -> VM {
+    let input = format!("{}\n", source).into_bytes();
+    VM::with_input(Cursor::new(input))
+}
+
+// run the prelude and then the VM's input until the input runs out
+fn run_vm(mut vm: VM) -> Run {
+    let output = Output::default();
+    vm.set_output(output.clone());
+    vm.init();
+    let mut errors = Vec::new();
+    let mut started = false;
+    while vm.is_running() {
+        let result = vm.step();
+        if !started && matches!(vm.current_location(), Some(("<stdin>", _))) {
+            // throw away what the prelude printed, and the prompt; this
+            // step has only read the first word of the input, so unless
+            // that was a primitive it hasn't printed anything yet
+            started = true;
+            output.0.borrow_mut().clear();
+        }
+        if let Err(e) = result {
+            if e.kind != VMErrorKind::Terminated {
+                errors.push(e.kind);
+            }
+        }
+        vm.take_errors();
+    }
+    let output = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+    Run { vm, output, errors }
+}
+
+fn run_with_limits(source: &str, limits: Limits) -> Run {
+    let mut vm = vm_for(source);
+    vm.set_limits(limits);
+    run_vm(vm)
+}
+
+fn run(source: &str) -> Run {
+    run_with_limits(
+        source,
+        Limits {
+            max_steps: Some(10_000_000),
+            ..Limits::default()
+        },
+    )
+}
+
+fn stack_of(source: &str) -> Vec<SignedCell> {
+    let run = run(source);
+    assert_eq!(run.errors, [], "errors running {:?}", source);
+    run.stack()
+}
+
+fn errors_of(source: &str) -> Vec<VMErrorKind> {
+    run(source).errors
+}
+
+// a path in the temporary directory which is unique to this test
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("froth-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn stack_ops() {
+    assert_eq!(stack_of("1 2 swap"), [2, 1]);
+    assert_eq!(stack_of("1 dup"), [1, 1]);
+    assert_eq!(stack_of("1 2 drop"), [1]);
+    assert_eq!(stack_of("7 8 9 depth"), [7, 8, 9, 3]);
+    assert_eq!(stack_of(": f >r 1 r> ; 2 f"), [1, 2]);
+}
+
+#[test]
+fn arithmetic() {
+    assert_eq!(stack_of("2 3 +"), [5]);
+    assert_eq!(stack_of("2 3 -"), [-1]);
+    assert_eq!(stack_of("-4 3 *"), [-12]);
+    assert_eq!(stack_of("17 5 /mod"), [2, 3]);
+    assert_eq!(
+        stack_of(&format!("{} 1 +", SignedCell::MAX)),
+        [SignedCell::MIN]
+    );
+}
+
+#[test]
+fn division() {
+    assert_eq!(stack_of("-7 2 /mod 7 -2 /mod"), [-1, -3, 1, -3]);
+    assert_eq!(stack_of("-7 2 / -7 2 mod 7 -2 / 7 -2 mod"), [-3, -1, -3, 1]);
+    assert_eq!(stack_of("-7 s>d 2 sm/rem 7 s>d -2 sm/rem"), [-1, -3, 1, -3]);
+    assert_eq!(stack_of("-7 s>d 2 fm/mod 7 s>d -2 fm/mod"), [1, -4, -1, -4]);
+    assert_eq!(stack_of("-8 s>d 2 fm/mod"), [0, -4]);
+    // the dividend of um/mod is a double cell, least significant cell first
+    assert_eq!(stack_of("-1 0 2 um/mod"), [1, SignedCell::MAX]);
+    assert_eq!(stack_of("0 1 2 um/mod"), [0, SignedCell::MIN]);
+    // the product is a double cell, so it can exceed a single cell
+    assert_eq!(
+        stack_of(&format!("{} 4 8 */", SignedCell::MAX)),
+        [SignedCell::MAX / 2]
+    );
+    assert_eq!(stack_of("7 3 2 */mod -7 3 2 */"), [1, 10, -10]);
+    assert_eq!(
+        run("-7 . -1 u. 255 hex u.").output,
+        format!("-7 {} ff ", Cell::MAX)
+    );
+    assert_eq!(errors_of("1 0 /mod"), [VMErrorKind::MathError]);
+    assert_eq!(errors_of("0 1 1 um/mod"), [VMErrorKind::MathError]);
+    assert_eq!(
+        errors_of(&format!("{} -1 /", SignedCell::MIN)),
+        [VMErrorKind::MathError]
+    );
+}
+
+#[test]
+fn shifts_and_bits() {
+    let bits = Cell::BITS as SignedCell;
+    assert_eq!(stack_of("-1 0 u< 0 -1 u< -1 0 u> 3 3 u<"), [0, 1, 1, 0]);
+    assert_eq!(
+        stack_of("1 4 lshift 256 4 rshift -16 2 arshift"),
+        [16, 16, -4]
+    );
+    assert_eq!(stack_of("-1 1 rshift 0 <"), [0]);
+    assert_eq!(
+        stack_of(&format!(
+            "1 {bits} lshift -1 {bits} rshift -1 {bits} arshift"
+        )),
+        [0, 0, -1]
+    );
+    assert_eq!(stack_of("3 2* -3 2* 7 2/ -7 2/"), [6, -6, 3, -4]);
+    assert_eq!(stack_of("-3 abs 3 abs 0 abs"), [3, 3, 0]);
+    assert_eq!(
+        stack_of(&format!("{} abs", SignedCell::MIN)),
+        [SignedCell::MIN]
+    );
+    assert_eq!(stack_of("-1 2 min -1 2 max 5 5 min"), [-1, 2, 5]);
+    assert_eq!(
+        stack_of("2 1 5 within 5 1 5 within 0 1 5 within -1 -3 2 within"),
+        [1, 0, 0, 1]
+    );
+    // a range that wraps round, from 5 up past the largest number to 1
+    assert_eq!(stack_of("-1 5 1 within 3 5 1 within"), [1, 0]);
+    assert_eq!(stack_of("5 0 bit? 5 1 bit? 5 2 bit?"), [1, 0, 1]);
+    assert_eq!(stack_of("5 1 set-bit 5 2 clear-bit 5 0 set-bit"), [7, 1, 5]);
+    assert_eq!(
+        stack_of(&format!(
+            "-1 {bits} bit? 5 {bits} set-bit 5 {bits} clear-bit"
+        )),
+        [0, 5, 5]
+    );
+}
+
+#[test]
+fn comparison_and_logic() {
+    assert_eq!(stack_of("3 3 = 3 4 ="), [1, 0]);
+    assert_eq!(stack_of("-1 0 < 0 -1 <"), [1, 0]);
+    assert_eq!(stack_of("-1 0 > 0 -1 >"), [0, 1]);
+    assert_eq!(stack_of("12 10 and 12 10 or 12 10 xor"), [8, 14, 6]);
+    assert_eq!(stack_of("0 invert"), [-1]);
+}
+
+#[test]
+fn memory_access() {
+    let run = run("here @ 1234 , here @ 0 c, 0 c, 0 c, 0 c, 65 over c! 66 over 1+ c!");
+    assert_eq!(run.errors, []);
+    let stack = run.stack();
+    assert_eq!(run.cell(stack[0] as Cell), 1234);
+    assert_eq!(&run.vm.memory()[stack[1] as usize..][..2], b"AB");
+    assert_eq!(
+        stack_of("here @ 42 , @ here @ 7 c, c@ align here @ 99 over ! @"),
+        [42, 7, 99]
+    );
+}
+
+#[test]
+fn dictionary_space() {
+    assert_eq!(
+        stack_of("here @ 1 c, align here @ swap -"),
+        [CELL_SIZE as SignedCell]
+    );
+    assert_eq!(stack_of("here @ 10 allot here @ swap -"), [10]);
+    assert_eq!(stack_of("unused 8 allot unused -"), [8]);
+}
+
+#[test]
+fn cell_size() {
+    let cell = CELL_SIZE as SignedCell;
+    assert_eq!(
+        stack_of("cell 3 cells 0 cell+ 10 cell-"),
+        [cell, 3 * cell, cell, 10 - cell]
+    );
+    assert_eq!(stack_of("here @ 1 , 2 , here @ swap -"), [2 * cell]);
+    assert_eq!(stack_of("-1 0 <"), [1]);
+    assert_eq!(stack_of("here @ -1 over ! @"), [-1]);
+}
+
+#[test]
+fn heap() {
+    assert_eq!(stack_of("100 allocate swap 0<>"), [0, 1]);
+    assert_eq!(stack_of("100 allocate drop free"), [0]);
+    assert_eq!(
+        stack_of("100 allocate drop 5 over c! 200 resize drop c@"),
+        [5]
+    );
+    assert_eq!(stack_of("12 free"), [-60]);
+    // sizes too big to round up to whole cells fail rather than wrapping
+    assert_eq!(stack_of("-1 allocate nip -3 allocate nip"), [-59, -59]);
+    assert_eq!(
+        stack_of(": f 100 allocate drop dup -1 resize >r = r> ; f"),
+        [1, -61]
+    );
+}
+
+#[test]
+fn heap_takes_only_what_is_allocated() {
+    let path = temp_path("heap.fb");
+    let mut vm =
+        vm_for(": f 100 allocate drop 5 over c! 7 over 99 + c! dup c@ swap 99 + c@ ; f 1 block c@");
+    vm.set_blocks_path(&path);
+    vm.set_limits(Limits {
+        max_memory: Some(1_500_000_000),
+        ..Limits::default()
+    });
+    let run = run_vm(vm);
+    assert_eq!(run.errors, []);
+    assert_eq!(run.stack(), [5, 7, ' ' as SignedCell]);
+    // the dictionary's memory doesn't grow to reach the heap
+    assert!(run.vm.memory().len() < 100_000);
+}
+
+#[test]
+fn colon_definitions() {
+    assert_eq!(stack_of(": sq dup * ; 7 sq"), [49]);
+    assert_eq!(stack_of(": a 1 exit 2 ; a"), [1]);
+    assert_eq!(
+        stack_of(": sign dup 0< if drop -1 else 0> if 1 else 0 then then ; -5 sign 0 sign 5 sign"),
+        [-1, 0, 1]
+    );
+    assert_eq!(
+        stack_of(": count-down begin dup 1- dup 0= until ; 3 count-down"),
+        [3, 2, 1, 0]
+    );
+}
+
+#[test]
+fn patching_code_that_has_run() {
+    // a definition patched after it has run runs the new code; inlining
+    // and tail calls would change the cell being patched, so they're off
+    let mut vm = vm_for(
+        ": one 1 ; : two 2 ; : f one ; f latest @ >cfa cell+ cell negate and : patch ' two swap ! ; patch f",
+    );
+    vm.set_inlining(false);
+    vm.set_peephole(false);
+    let run = run_vm(vm);
+    assert_eq!(run.errors, []);
+    assert_eq!(run.stack(), [1, 2]);
+}
+
+#[test]
+fn peephole_optimiser() {
+    let snippets: &[(&str, &[SignedCell])] = &[
+        (": f 5 + ; 1 f", &[6]),
+        (": f dup if 1 else 2 then ; 0 f 3 f", &[0, 2, 3, 1]),
+        (": f swap drop ; 1 2 f", &[2]),
+        (": f over over ; 1 2 f", &[1, 2, 1, 2]),
+        // a branch to the `+` of `lit n +` must not skip the `+`
+        (": f 10 swap if 1 then + ; 5 0 f 5 1 f", &[15, 5, 11]),
+        // only the prelude's over is fused
+        (": over 7 ; : f over over ; f", &[7, 7]),
+    ];
+    for &(source, expected) in snippets {
+        assert_eq!(stack_of(source), expected, "{}", source);
+        let mut vm = vm_for(source);
+        vm.set_peephole(false);
+        let run = run_vm(vm);
+        assert_eq!(run.errors, []);
+        assert_eq!(run.stack(), expected, "{} unoptimised", source);
+    }
+    // the first cell of a fused sequence is replaced; this leaves it and
+    // the xt of lit on the stack
+    let first_cell = ": f 5 + ; : lit-xt ' lit ; latest @ @ >cfa cell+ cell negate and @ lit-xt";
+    let optimised = stack_of(first_cell);
+    assert_ne!(optimised[0], optimised[1]);
+    let mut vm = vm_for(first_cell);
+    vm.set_peephole(false);
+    let run = run_vm(vm);
+    assert_eq!(run.errors, []);
+    assert_eq!(run.stack()[0], run.stack()[1]);
+}
+
+// defines first-cell ( header -- x ), the first cell of a colon
+// definition's body
+const FIRST_CELL: &str = ": first-cell >cfa cell+ cell negate and @ ;";
+
+#[test]
+fn inlining_short_definitions() {
+    // f starts with a copy of the body of 1+, or else a call to it
+    let source = format!(
+        "{} : f 1+ 0 ; latest @ first-cell bl word 1+ find dup first-cell swap >cfa",
+        FIRST_CELL
+    );
+    let inlined = stack_of(&source);
+    assert_eq!(inlined[0], inlined[1]);
+    let mut vm = vm_for(&source);
+    vm.set_inlining(false);
+    let run = run_vm(vm);
+    assert_eq!(run.errors, []);
+    assert_eq!(run.stack()[0], run.stack()[2]);
+}
+
+#[test]
+fn inlining_longer_definitions() {
+    // g is too long to be copied unless it is marked inline
+    let first_cell = |marking: &str| {
+        let source = format!(
+            "{} : g 1 2 3 4 5 ; {} : f g 0 ; latest @ first-cell bl word g find dup first-cell swap >cfa",
+            FIRST_CELL, marking
+        );
+        let cells = stack_of(&source);
+        (cells[0], cells[1], cells[2])
+    };
+    let (first, _, xt) = first_cell("");
+    assert_eq!(first, xt);
+    let (first, body, _) = first_cell("inline");
+    assert_eq!(first, body);
+    assert_eq!(
+        stack_of(": g 1 2 3 4 5 ; inline : f g ; f"),
+        [1, 2, 3, 4, 5]
+    );
+}
+
+#[test]
+fn inlined_control_flow() {
+    // an exit before the end of a copy jumps to the end of it
+    assert_eq!(
+        stack_of(": g dup 0< if drop 0 exit then ; inline : f g 1+ ; -5 f 5 f"),
+        [1, 6]
+    );
+    // and a branch within the copy still finds its target
+    assert_eq!(
+        stack_of(": g begin 1- dup 0= until ; inline : f g 7 ; 3 f"),
+        [0, 7]
+    );
+    // a word that uses its caller's return address is still called
+    assert_eq!(stack_of(": x r> drop ; : f x 2 ; : g f 3 ; g"), [3]);
+    // as does activate, which returns from go to start
+    assert_eq!(
+        stack_of("task t : go t activate pause ; : start go 99 ; start"),
+        [99]
+    );
+    // and the word after ' is compiled as an xt rather than copied
+    assert_eq!(stack_of(": f ' 1+ ; 5 f execute"), [6]);
+}
+
+#[test]
+fn tail_calls() {
+    let limits = Limits {
+        max_return_stack: Some(8),
+        max_steps: Some(1_000_000),
+        ..Limits::default()
+    };
+    // the recursive calls don't use the return stack, so these run in it
+    let snippets: &[(&str, &[SignedCell])] = &[
+        (": f dup if 1- recurse then ; 1000 f", &[0]),
+        (": f dup 0> if 1- recurse exit then drop 7 ; 1000 f", &[7]),
+        (": f dup 0= if else 1- recurse then ; 1000 f", &[0]),
+    ];
+    for &(source, expected) in snippets {
+        let run = run_with_limits(source, limits);
+        assert_eq!(run.errors, [], "{}", source);
+        assert_eq!(run.stack(), expected, "{}", source);
+        let mut vm = vm_for(source);
+        vm.set_limits(limits);
+        vm.set_peephole(false);
+        let run = run_vm(vm);
+        assert_eq!(
+            run.errors,
+            [VMErrorKind::ReturnStackOverflow("f".into())],
+            "{} unoptimised",
+            source
+        );
+    }
+    // x takes its caller's return address, so f still calls it, and calls
+    // to words that call x are kept too
+    assert_eq!(stack_of(": x r> drop ; : f 1 x ; : g f 3 ; g"), [1, 3]);
+    assert_eq!(
+        stack_of(": x r> drop r> drop ; : y x 2 ; : f 1 y ; : g f 3 ; g 4"),
+        [1, 3, 4]
+    );
+}
+
+#[test]
+fn compiling_words() {
+    // ' (tick) compiles the following word as a literal xt
+    assert_eq!(stack_of(": xt ' dup ; 5 xt execute"), [5, 5]);
+    assert_eq!(stack_of(": five [ 2 3 + ] literal ; five"), [5]);
+    assert_eq!(stack_of(": gr 1 ; immediate : uses gr ; uses"), [1]);
+    assert_eq!(stack_of("state @ ] [ state @"), [0, 0]);
+    assert_eq!(stack_of(": str s\" hello\" swap drop ; str"), [5]);
+    // a hidden word can't be found
+    assert_eq!(
+        errors_of(": x 1 ; latest @ hidden x"),
+        [VMErrorKind::UnknownWord("x".into())]
+    );
+}
+
+#[test]
+fn dictionary_lookup() {
+    // word's buffer is reused by the interpreter, so use it inside a definition
+    assert_eq!(
+        stack_of(": lookup bl word find ; 1 lookup dup >cfa execute"),
+        [1, 1]
+    );
+    assert_eq!(stack_of(": lookup bl word find ; lookup nonesuch"), [0]);
+    assert_eq!(
+        stack_of(": parse bl word number ; parse 123x parse -42"),
+        [123, 1, -42, 0]
+    );
+    let run = run(": make bl word create ; make foo latest @");
+    assert_eq!(run.errors, []);
+    let header = run.stack()[0] as usize;
+    let name = header + CELL_SIZE as usize;
+    assert_eq!(run.vm.memory()[name], 3);
+    assert_eq!(&run.vm.memory()[name + 1..name + 4], b"foo");
+}
+
+#[test]
+fn failed_create_leaves_the_dictionary_alone() {
+    let run = run("create\n: sq dup * ; 3 sq bl word sq find latest @ = words");
+    assert_eq!(run.errors, [VMErrorKind::DataStackUnderflow]);
+    assert_eq!(run.stack(), [9, 1]);
+    assert!(run.output.contains("sq count-words"));
+}
+
+#[test]
+fn input_and_output() {
+    assert_eq!(stack_of("key A"), ['A' as SignedCell]);
+    assert_eq!(run("72 emit 105 emit").output, "Hi");
+    assert_eq!(run(".\" hello\" cr").output, "hello\n");
+    assert_eq!(run("1 2 3 .s").output, "<3>  1 2 3 ");
+    assert_eq!(run("-7 . 255 hex . decimal").output, "-7 ff ");
+}
+
+#[test]
+fn utf8() {
+    assert_eq!(stack_of(": café 1 ; café"), [1]);
+    assert_eq!(
+        errors_of("naïve"),
+        [VMErrorKind::UnknownWord("naïve".into())]
+    );
+    // emit passes bytes through, so strings print as they were written
+    assert_eq!(run("195 emit 169 emit").output, "é");
+    assert_eq!(run(".\" naïve €\"").output, "naïve €");
+    assert_eq!(run("233 xemit 8364 xemit").output, "é€");
+    assert_eq!(stack_of("xkey €"), [8364]);
+    // € is 3 bytes of UTF-8
+    assert_eq!(stack_of("here @ 8364 over xc!+ swap -"), [3]);
+    assert_eq!(
+        stack_of("here @ 8364 over xc!+ drop xc@+ swap drop"),
+        [8364]
+    );
+    assert_eq!(stack_of("here @ 8364 over xc!+ drop 3 x-size"), [3]);
+    assert_eq!(stack_of("here @ 65 over xc!+ drop 1 x-size"), [1]);
+    // a byte that isn't part of a valid sequence is a character by itself
+    assert_eq!(stack_of("here @ 255 over c! xc@+ swap here @ -"), [255, 1]);
+}
+
+#[test]
+fn strings() {
+    let words = ": hello s\" hello world  \" ; : ll s\" ll\" ; : xyz s\" xyz\" ; ";
+    let stack = |code: &str| stack_of(&format!("{words}{code}"));
+    assert_eq!(
+        stack("hello hello compare ll hello compare hello ll compare"),
+        [0, 1, -1]
+    );
+    assert_eq!(
+        stack("hello drop 5 hello compare hello 0 0 compare"),
+        [-1, 1]
+    );
+    assert_eq!(stack("hello ll search rot hello drop - swap"), [11, 2, 1]);
+    assert_eq!(stack("hello xyz search nip nip"), [0]);
+    assert_eq!(stack("hello 0 0 search nip nip"), [1]);
+    assert_eq!(
+        stack("hello 6 /string drop c@ hello 6 /string nip"),
+        [119, 7]
+    );
+    assert_eq!(stack("hello -trailing nip"), [11]);
+    assert_eq!(run(&format!("{words}hello type")).output, "hello world  ");
+    // a counted string starts with its length
+    assert_eq!(stack("here @ 3 over c! dup count swap rot -"), [3, 1]);
+
+    // cmove copies from the lowest address up, so copying to a higher
+    // address that overlaps repeats the first byte; cmove> and move don't
+    let fill = "here @ 4 erase 1 here @ c! 2 here @ 1+ c! ";
+    let bytes = "here @ c@ here @ 1+ c@ here @ 2 + c@ here @ 3 + c@";
+    assert_eq!(
+        stack(&format!("{fill}here @ dup 1+ 3 cmove {bytes}")),
+        [1, 1, 1, 1]
+    );
+    assert_eq!(
+        stack(&format!("{fill}here @ dup 1+ 3 cmove> {bytes}")),
+        [1, 1, 2, 0]
+    );
+    assert_eq!(
+        stack(&format!("{fill}here @ dup 1+ 3 move {bytes}")),
+        [1, 1, 2, 0]
+    );
+    assert_eq!(stack(&format!("here @ 4 65 fill {bytes}")), [65; 4]);
+    assert_eq!(stack(&format!("here @ 4 blank {bytes}")), [32; 4]);
+    assert_eq!(stack(&format!("here @ 4 erase {bytes}")), [0; 4]);
+
+    // nothing is touched by an empty range, wherever it is
+    assert_eq!(errors_of("-4 0 erase -4 -8 0 move -4 0 type"), []);
+    assert_eq!(
+        errors_of("-4 4 erase"),
+        [VMErrorKind::IllegalAddress(-4 as SignedCell as Cell)]
+    );
+    assert_eq!(
+        errors_of("-4 here @ 4 move"),
+        [VMErrorKind::IllegalAddress(-4 as SignedCell as Cell)]
+    );
+}
+
+#[test]
+fn input_buffer() {
+    // source is the line being interpreted, and >in how far through it
+    // the interpreter has got
+    assert_eq!(stack_of("source nip"), [10]);
+    assert_eq!(stack_of(">in @"), [5]);
+    assert_eq!(stack_of("source nip >in ! 1 2\n3"), [3]);
+    assert_eq!(stack_of("1 2 \\ 3\n4"), [1, 2, 4]);
+    // parse-name skips leading spaces and parse doesn't
+    assert_eq!(stack_of("parse-name   abc nip 41 parse  de) nip"), [3, 3]);
+    assert_eq!(stack_of("41 parse ) nip"), [0]);
+    assert_eq!(stack_of("58 word ::ab: nip"), [2]);
+    assert_eq!(
+        stack_of(": bracketed 93 word ; bracketed ]]xyz] drop c@"),
+        ['x' as SignedCell]
+    );
+    // refill reads the next line in place of the rest of this one
+    assert_eq!(stack_of("refill 99\n42 source nip"), [1, 42, 13]);
+    // key reads the source too, and a word that ends a line takes the end
+    // of the line with it, as whitespace after a word always did
+    assert_eq!(stack_of("key z"), ['z' as SignedCell]);
+    assert_eq!(stack_of("key\nz"), ['z' as SignedCell]);
+    assert_eq!(stack_of(": k key key ; k\na"), ['a' as SignedCell, 10]);
+    // a line longer than the input buffer is read in pieces
+    let long = format!("{}7", " ".repeat(3000));
+    assert_eq!(stack_of(&long), [7]);
+}
+
+#[test]
+fn evaluate() {
+    assert_eq!(stack_of("s\" 1 2 +\" evaluate"), [3]);
+    assert_eq!(
+        stack_of(": inner s\" 4 5\" ; s\" inner evaluate +\" evaluate"),
+        [9]
+    );
+    // a string that s" left at here survives what it compiles
+    assert_eq!(stack_of("s\" : sq dup * ;\" evaluate 5 sq"), [25]);
+    // a definition can start or end in the evaluated text
+    assert_eq!(
+        stack_of(": mk s\" : cube dup dup\" evaluate ; mk * * ; 3 cube"),
+        [27]
+    );
+    assert_eq!(stack_of(": g 1 [ s\" ] 2 ;\" evaluate g"), [1, 2]);
+    // source is the string itself
+    assert_eq!(
+        stack_of(": src s\" source\" ; src 2dup evaluate rot = rot rot ="),
+        [1, 1]
+    );
+    // the text after evaluate is interpreted once the string runs out,
+    // or fails
+    assert_eq!(stack_of("s\" 6\" evaluate 7"), [6, 7]);
+    let failed = run("s\" 1 foo 2\" evaluate\n3");
+    assert_eq!(failed.errors, [VMErrorKind::UnknownWord("foo".into())]);
+    assert_eq!(failed.stack(), [1, 3]);
+}
+
+#[test]
+fn quit_resets_the_return_stack() {
+    let run = run(": f 1 >r quit ; f");
+    assert_eq!(run.errors, []);
+    assert_eq!(run.stack(), []);
+}
+
+#[test]
+fn tasks() {
+    // a task runs whenever the outer interpreter pauses between words, and
+    // has its own stacks and user area
+    let counting =
+        run("task t : job t activate 0 begin 1+ dup up@ ! pause again ; job 7 8 t @ up@ t =");
+    assert_eq!(counting.errors, []);
+    let stack = counting.stack();
+    assert_eq!(stack[..2], [7, 8]);
+    assert!(stack[2] > 0);
+    assert_eq!(stack[3], 0);
+    // a task that stops sleeps until it's woken
+    assert_eq!(
+        stack_of(
+            "task t : job t activate begin 1 up@ +! stop again ; job 1 drop t @ t wake 1 drop t @"
+        ),
+        [1, 2]
+    );
+    // and one that gets to the end of its code stops
+    assert_eq!(
+        stack_of("task t : job t activate 5 up@ ! ; job 1 drop t @ 1 drop"),
+        [5]
+    );
+    // an error in a task stops it and leaves the rest alone
+    let failed = run("task t : job t activate drop ; job 1 2 +");
+    assert_eq!(failed.errors, [VMErrorKind::DataStackUnderflow]);
+    assert_eq!(failed.stack(), [3]);
+    assert_eq!(
+        errors_of("task t t activate"),
+        [VMErrorKind::ReturnStackUnderflow]
+    );
+    assert_eq!(errors_of("5 wake"), [VMErrorKind::InvalidTask(5)]);
+    // a task that hasn't been activated has nothing to run
+    let idle = run("task t t t wake");
+    assert_eq!(
+        idle.errors,
+        [VMErrorKind::InvalidTask(idle.stack()[0] as Cell)]
+    );
+}
+
+#[test]
+fn files() {
+    let path = temp_path("files.txt");
+    let moved = temp_path("moved.txt");
+    let source = format!(
+        r#"
+        : name s" {path}" ;
+        : moved s" {moved}" ;
+        here @ 0 , : fd literal ;
+        name w/o create-file drop fd !
+        s" first line" fd @ write-line drop
+        s" second" fd @ write-file drop
+        fd @ file-position drop drop
+        fd @ file-size drop drop
+        fd @ close-file drop
+        name r/o open-file drop fd !
+        here @ 100 fd @ read-line drop drop
+        0 0 fd @ reposition-file drop
+        here @ 5 fd @ read-file drop
+        fd @ close-file drop
+        name moved rename-file
+        moved delete-file
+        "#,
+        path = path.display(),
+        moved = moved.display()
+    );
+    let run = run(&source);
+    assert_eq!(run.errors, []);
+    assert_eq!(run.stack(), [17, 17, 10, 5, 0, 0]);
+    assert!(!path.exists());
+    assert!(!moved.exists());
+}
+
+#[test]
+fn read_file_into_a_bad_buffer() {
+    let path = temp_path("bad-buffer.txt");
+    std::fs::write(&path, "hello world").unwrap();
+    let source = format!(
+        r#"
+        here @ 0 , : fd literal ;
+        s" {path}" r/o open-file drop fd !
+        -8 5 fd @ read-file
+        here @ 5 fd @ read-file here @ c@
+        fd @ close-file drop
+        "#,
+        path = path.display()
+    );
+    let run = run(&source);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(run.errors, [VMErrorKind::IllegalAddress(-8i32 as Cell)]);
+    // nothing was read by the failed read-file
+    assert_eq!(run.stack(), [5, 0, 'h' as SignedCell]);
+}
+
+#[test]
+fn file_access_turned_off() {
+    let path = temp_path("forbidden.txt");
+    let blocks = temp_path("forbidden.fb");
+    let source = format!(
+        r#"
+        : name s" {path}" ;
+        name w/o create-file
+        name r/o open-file
+        name name rename-file
+        name delete-file
+        1 block
+        2 buffer drop update flush
+        "#,
+        path = path.display()
+    );
+    let mut vm = vm_for(&source);
+    vm.set_file_access(false);
+    vm.set_blocks_path(&blocks);
+    let run = run_vm(vm);
+    assert_eq!(run.errors, [VMErrorKind::IOError, VMErrorKind::IOError]);
+    assert_eq!(run.stack(), [0, -37, 0, -37, -37, -37]);
+    assert!(!path.exists());
+    assert!(!blocks.exists());
+}
+
+#[test]
+fn blocks() {
+    let path = temp_path("blocks.fb");
+    let mut vm = vm_for(
+        r#"
+        : text s" 6 7 *" ;
+        : blank ( addr -- ) 1024 begin dup while >r 32 over c! 1+ r> 1- repeat 2drop ;
+        : copy ( from to n -- ) begin dup while >r over c@ over c! 1+ swap 1+ swap r> 1- repeat drop 2drop ;
+        1 buffer dup blank text rot swap copy update save-buffers
+        flush 1 load
+        1 block c@
+        "#,
+    );
+    vm.set_blocks_path(&path);
+    let run = run_vm(vm);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(run.errors, []);
+    assert_eq!(run.stack(), [42, '6' as SignedCell]);
+}
+
+#[cfg(feature = "cell64")]
+#[test]
+fn block_offsets_that_overflow() {
+    let path = temp_path("overflow.fb");
+    std::fs::write(&path, [b' '; 1024]).unwrap();
+    let mut vm = vm_for("1152921504606846977 block 18014398509481984 buffer");
+    vm.set_blocks_path(&path);
+    let run = run_vm(vm);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(
+        run.errors,
+        [
+            VMErrorKind::InvalidBlock(1152921504606846977),
+            VMErrorKind::InvalidBlock(18014398509481984)
+        ]
+    );
+}
+
+#[test]
+fn tester_words() {
+    let run = run("T{ 1 2 + -> 3 }T T{ 1 -> 2 }T T{ 1 -> }T");
+    assert_eq!(run.vm.test_counts(), (1, 2));
+    assert_eq!(
+        run.output,
+        "<stdin>:1: incorrect result: expected <1> 2, got <1> 1\n\
+         <stdin>:1: wrong number of results: expected <0> , got <1> 1\n"
+    );
+}
+
+#[test]
+fn profiler() {
+    // with inlining and tail calls off, each word keeps its own frame
+    let mut vm = vm_for(": sq dup * ; : f sq sq ; : g 3 f 2 + ; g");
+    vm.set_inlining(false);
+    vm.set_peephole(false);
+    vm.enable_profiling();
+    let run = run_vm(vm);
+    assert_eq!(run.stack(), [83]);
+
+    // calls, inclusive and exclusive counts: sq runs dup, * and exit,
+    // f adds itself and its exit, and g adds itself, two lits, + and exit
+    let report = run.vm.profile_report().unwrap();
+    let counts = |word: &str| -> Vec<u64> {
+        let line = report
+            .lines()
+            .find(|line| line.split_whitespace().next() == Some(word))
+            .unwrap();
+        line.split_whitespace()
+            .skip(1)
+            .map(|n| n.parse().unwrap())
+            .collect()
+    };
+    assert_eq!(counts("sq"), [2, 8, 8]);
+    assert_eq!(counts("f"), [1, 10, 2]);
+    assert_eq!(counts("g"), [1, 15, 5]);
+
+    let mut folded = Vec::new();
+    run.vm.write_folded_stacks(&mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    let under_g: Vec<&str> = folded
+        .lines()
+        .filter(|line| line.starts_with("g ") || line.starts_with("g;"))
+        .collect();
+    assert_eq!(
+        under_g,
+        [
+            "g 1",
+            "g;+ 1",
+            "g;exit 1",
+            "g;f 1",
+            "g;f;exit 1",
+            "g;f;sq 2",
+            "g;f;sq;* 2",
+            "g;f;sq;dup 2",
+            "g;f;sq;exit 2",
+            "g;lit 2",
+        ]
+    );
+}
+
+#[test]
+fn coverage() {
+    let path = temp_path("coverage.f");
+    let source = "\
+: sign ( n -- n' )
+  0< if
+    -1
+  else
+    1
+  then ;
+: unused 42 ;
+5 sign
+";
+    std::fs::write(&path, source).unwrap();
+    let mut vm = vm_for("");
+    vm.enable_coverage();
+    vm.include_file(&path).unwrap();
+    let run = run_vm(vm);
+    assert_eq!(run.stack(), [1]);
+
+    let mut lcov = Vec::new();
+    run.vm.write_lcov(&mut lcov).unwrap();
+    let lcov = String::from_utf8(lcov).unwrap();
+    let record: Vec<&str> = lcov
+        .split("end_of_record\n")
+        .find(|record| record.contains(&format!("SF:{}\n", path.display())))
+        .unwrap()
+        .lines()
+        .filter(|line| line.starts_with("DA:"))
+        .collect();
+    // the branch not taken (-1, and else's jump) and the word never
+    // called have lines with no hits; lines that compile nothing have
+    // no entry
+    assert_eq!(
+        record,
+        ["DA:2,1", "DA:3,0", "DA:4,0", "DA:5,1", "DA:6,1", "DA:7,0"]
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn unknown_opcode() {
+    let run = run("here @ 200 over c! execute");
+    match run.errors.as_slice() {
+        [VMErrorKind::UnknownOpcode { opcode: 200, .. }] => (),
+        errors => panic!("unexpected errors {:?}", errors),
+    }
+}
+
+#[test]
+fn stack_underflow() {
+    assert_eq!(errors_of("drop"), [VMErrorKind::DataStackUnderflow]);
+    assert_eq!(errors_of("1 +"), [VMErrorKind::DataStackUnderflow]);
+    assert_eq!(
+        errors_of(": f r> r> r> ; f"),
+        [VMErrorKind::ReturnStackUnderflow]
+    );
+}
+
+#[test]
+fn stack_overflow() {
+    let limits = Limits {
+        max_data_stack: Some(8),
+        max_return_stack: Some(8),
+        max_steps: Some(1_000_000),
+        ..Limits::default()
+    };
+    let run = run_with_limits(": f begin 1 0 until ; f", limits);
+    assert_eq!(run.errors, [VMErrorKind::DataStackOverflow("f".into())]);
+    let run = run_with_limits(": g recurse recurse ; g", limits);
+    assert_eq!(run.errors, [VMErrorKind::ReturnStackOverflow("g".into())]);
+}
+
+#[test]
+fn illegal_address() {
+    assert_eq!(
+        errors_of("-8 @"),
+        [VMErrorKind::IllegalAddress(-8 as SignedCell as Cell)]
+    );
+    assert_eq!(
+        errors_of("1 -1 c!"),
+        [VMErrorKind::IllegalAddress(Cell::MAX)]
+    );
+}
+
+#[test]
+fn unaligned_access() {
+    assert_eq!(errors_of("5 @"), [VMErrorKind::UnalignedAccess(5)]);
+    assert_eq!(errors_of("1 6 !"), [VMErrorKind::UnalignedAccess(6)]);
+}
+
+#[test]
+fn divide_by_zero() {
+    assert_eq!(errors_of("1 0 /mod"), [VMErrorKind::MathError]);
+}
+
+#[test]
+fn unknown_word() {
+    let run = run("1 frobnicate 2");
+    assert_eq!(run.errors, [VMErrorKind::UnknownWord("frobnicate".into())]);
+    // the rest of the line is still run after an error at the terminal
+    assert_eq!(run.stack(), [1, 2]);
+}
+
+#[test]
+fn resource_limits() {
+    let run = run_with_limits(
+        ": forever begin 0 until ; forever",
+        Limits {
+            max_steps: Some(100_000),
+            ..Limits::default()
+        },
+    );
+    assert_eq!(run.errors, [VMErrorKind::FuelExhausted]);
+    assert!(run.output.ends_with(" instruction limit exceeded\n"));
+    let run = run_with_limits(
+        "100000 allot",
+        Limits {
+            max_memory: Some(65536),
+            ..Limits::default()
+        },
+    );
+    assert_eq!(run.errors, [VMErrorKind::MemoryLimitExceeded]);
+    assert_eq!(errors_of("0 block"), [VMErrorKind::InvalidBlock(0)]);
+}
+
+#[test]
+fn too_many_errors() {
+    // without taking the errors as they happen, they pile up until the VM
+    // gives up, saying so through its output
+    let mut vm = vm_for(&"x ".repeat(11));
+    let output = Output::default();
+    vm.set_output(output.clone());
+    vm.init();
+    while vm.is_running() {
+        let _ = vm.step();
+    }
+    let output = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+    assert!(output.ends_with(" unknown word x\n too many errors, aborting\n"));
+    assert_eq!(vm.take_errors().len(), 10);
+}
+
+#[test]
+fn running_out_of_input() {
+    let run = run("key");
+    assert_eq!(run.errors, []);
+    assert!(!run.vm.is_running());
+}
+
+#[test]
+fn malformed_input_is_an_error_not_a_panic() {
+    assert_eq!(
+        stack_of("99999999999 -2147483648"),
+        [99999999999i64 as SignedCell, -2147483648]
+    );
+    assert_eq!(
+        errors_of("-1 execute"),
+        [VMErrorKind::IllegalAddress(Cell::MAX)]
+    );
+    assert_eq!(
+        errors_of("-1 here ! 1 ,"),
+        [VMErrorKind::MemoryLimitExceeded]
+    );
+    // a header linked to itself ends the search rather than looping
+    assert_eq!(
+        errors_of("latest @ dup ! nonesuch"),
+        [VMErrorKind::UnknownWord("nonesuch".into())]
+    );
+    // a buffer as large as the length given isn't made
+    assert_eq!(stack_of("here @ -1 99 read-line"), [0, 0, -37]);
+}